@@ -0,0 +1,1183 @@
+use crate::crypto::fill_random;
+use crate::erasure::{
+    Erasure,
+    SHARD_LEN
+};
+use crate::error::Error;
+use crate::header::Header;
+use crate::image_ops::{
+    is_png_file,
+    rgba_prefix,
+    ImageSource,
+    PixelReader,
+    PixelWriter
+};
+use anyhow::{
+    Result,
+    anyhow,
+    bail
+};
+use image::{
+    ColorType,
+    DynamicImage
+};
+use log::{
+    debug,
+    info,
+    warn
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+    Write
+};
+use std::path::{
+    Path,
+    PathBuf
+};
+
+/// Most bytes one noise image holds, 2^30 RGBA pixels. Larger carriers are
+/// spread over more images, so they stay within what decoders can open.
+pub const MAX_PART_LEN: u64 = 1 << 32;
+
+/// Widest noise image, a single row of it already holds `MAX_PART_LEN` bytes
+pub const MAX_WIDTH: u32 = (MAX_PART_LEN / 4) as u32;
+
+/// Noise image(s) a carrier is split into, with the number of header + ciphertext bytes in each
+struct Part {
+    image: PartImage,
+    len: u64,
+    header: Option<PartHeader>, // in front of the bytes of split parts
+}
+
+impl Part {
+    /// Bytes the image needs for the part header and data
+    fn image_len(&self) -> u64 {
+        self.len + self.header.map_or(0, |h| h.size() as u64)
+    }
+}
+
+/// Where the image of a part is
+#[derive(Clone)]
+enum PartImage {
+    File(PathBuf),
+    /// Index into the images of a carrier that isn't in files: images in
+    /// memory, image files read from readers or PNGs written to writers
+    Index(usize),
+}
+
+impl fmt::Debug for PartImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartImage::File(path) => write!(f, "{:?}", path),
+            PartImage::Index(index) => write!(f, "image {}", index + 1),
+        }
+    }
+}
+
+/// Image of a part that isn't a file, taken out of the carrier when it is read
+enum Unread<'r> {
+    Image(DynamicImage),
+    /// Image file read from a reader, which is rewound to `start` after looking at it
+    Reader { source: Box<dyn ImageSource + 'r>, start: u64 },
+}
+
+impl<'r> Unread<'r> {
+    fn open(self) -> Result<PixelReader<'r>> {
+        match self {
+            Unread::Image(img) => Ok(PixelReader::from_image(img)),
+            Unread::Reader { source, .. } => PixelReader::from_reader(source),
+        }
+    }
+
+    /// Part header at the start of the image, `None` if it has none
+    fn part_header(&mut self) -> Result<Option<PartHeader>> {
+        match self {
+            Unread::Image(img) => PartHeader::read(&mut &rgba_prefix(img, CODED_PART_HEADER_LEN)[..]),
+            Unread::Reader { source, start } => {
+                let header = PixelReader::from_reader(&mut *source).and_then(|mut r| PartHeader::read(&mut r));
+                source.seek(SeekFrom::Start(*start))?;
+                header
+            }
+        }
+    }
+
+    /// RGBA8 bytes the image holds, without reading its pixels
+    fn byte_len(&mut self) -> Result<u64> {
+        let (width, height) = match self {
+            Unread::Image(img) => (img.width(), img.height()),
+            Unread::Reader { source, start } => {
+                let dimensions = image::ImageReader::new(&mut *source).with_guessed_format()?.into_dimensions()?;
+                source.seek(SeekFrom::Start(*start))?;
+                dimensions
+            }
+        };
+        Ok(width as u64 * height as u64 * 4)
+    }
+}
+
+/// Open the image of a part for reading, images that aren't files are taken out of `images`
+fn open_image<'r>(image: &PartImage, images: &mut [Option<Unread<'r>>]) -> Result<PixelReader<'r>> {
+    match *image {
+        PartImage::File(ref path) => PixelReader::open(path),
+        PartImage::Index(index) => images.get_mut(index)
+            .and_then(Option::take)
+            .ok_or_else(|| anyhow!("Image {} was already read", index + 1))?
+            .open(),
+    }
+}
+
+/// Bytes the image of a part holds, without reading its pixels
+fn image_byte_len(image: &PartImage, images: &mut [Option<Unread>]) -> Result<u64> {
+    match *image {
+        PartImage::File(ref path) => Ok(PixelReader::open(path)?.byte_len()),
+        PartImage::Index(index) => images.get_mut(index)
+            .and_then(Option::as_mut)
+            .ok_or_else(|| anyhow!("Image {} was already read", index + 1))?
+            .byte_len(),
+    }
+}
+
+const PART_MAGIC: &[u8; 4] = b"P2WP";
+/// Magic, version, set ID, index, count and length
+pub const PART_HEADER_LEN: usize = 4 + 1 + 16 + 4 + 4 + 8;
+/// Version 2 adds the parts needed and the shard length of erasure coded parts
+const CODED_PART_HEADER_LEN: usize = PART_HEADER_LEN + 4 + 4;
+
+/// Starts every split part, so the parts of a carrier are found and put in
+/// order whatever their file names are
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PartHeader {
+    set_id: [u8; 16], // random UUID shared by all parts of a carrier
+    index: u32, // from 0
+    count: u32,
+    len: u64, // header + ciphertext bytes in the part, shard bytes if erasure coded
+    min_parts: u32, // parts needed, `count` unless erasure coded
+    shard_len: u32, // bytes of each stripe in the part, 0 unless erasure coded
+}
+
+impl PartHeader {
+    fn coded(&self) -> bool {
+        self.min_parts < self.count
+    }
+
+    fn size(&self) -> usize {
+        if self.coded() { CODED_PART_HEADER_LEN } else { PART_HEADER_LEN }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(PART_MAGIC);
+        bytes.push(if self.coded() { 2 } else { 1 });
+        bytes.extend_from_slice(&self.set_id);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        if self.coded() {
+            bytes.extend_from_slice(&self.min_parts.to_be_bytes());
+            bytes.extend_from_slice(&self.shard_len.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Part header at the start of an image, `None` if it has none
+    fn read<R: Read>(source: &mut R) -> Result<Option<Self>> {
+        let mut bytes = vec![0u8; 5];
+        match source.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if bytes[..4] != *PART_MAGIC {
+            return Ok(None);
+        }
+        let size = match bytes[4] {
+            1 => PART_HEADER_LEN,
+            2 => CODED_PART_HEADER_LEN,
+            version => bail!(Error::UnsupportedVersion(format!("Unsupported part header version {}", version))),
+        };
+        bytes.resize(size, 0);
+        source.read_exact(&mut bytes[5..])?;
+        let mut header = Self {
+            set_id: bytes[5..21].try_into()?,
+            index: u32::from_be_bytes(bytes[21..25].try_into()?),
+            count: u32::from_be_bytes(bytes[25..29].try_into()?),
+            len: u64::from_be_bytes(bytes[29..37].try_into()?),
+            min_parts: 0,
+            shard_len: 0,
+        };
+        header.min_parts = header.count;
+        if size == CODED_PART_HEADER_LEN {
+            header.min_parts = u32::from_be_bytes(bytes[37..41].try_into()?);
+            header.shard_len = u32::from_be_bytes(bytes[41..45].try_into()?);
+            if !header.coded() || header.min_parts == 0 || header.shard_len == 0 {
+                bail!(Error::Invalid(format!("Invalid erasure coding, {} of {} parts with {} byte shards", header.min_parts, header.count, header.shard_len)));
+            }
+        }
+        if header.index >= header.count {
+            bail!(Error::Invalid(format!("Invalid part {} of {}", header.index + 1, header.count)));
+        }
+        Ok(Some(header))
+    }
+
+    /// Read the part header of the next image and check it is the one expected
+    fn expect(self, source: &mut PixelReader, image: &PartImage) -> Result<()> {
+        if Self::read(source)? != Some(self) {
+            bail!(Error::Invalid(format!("{:?} is no longer part {} of set {}", image, self.index + 1, set_name(&self.set_id))));
+        }
+        Ok(())
+    }
+}
+
+/// New random set ID, formatted like a version 4 UUID
+fn new_set_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    fill_random(&mut id);
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+/// Set ID as a UUID string
+fn set_name(id: &[u8; 16]) -> String {
+    let hex = hex::encode(id);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Rows needed for `len` bytes in RGBA8 pixels
+fn rows(len: u64, width: u32) -> Result<u32> {
+    let height = len.div_ceil(4).div_ceil(width as u64);
+    u32::try_from(height).map_err(|_| Error::Invalid(format!("Carrier image too large ({} rows)", height)).into())
+}
+
+/// Width of a carrier for `len` bytes with a `ratio` = width / height
+fn ratio_width(len: u64, ratio: f64) -> u32 {
+    let pixels = len.div_ceil(4) as f64;
+    ((pixels * ratio).sqrt().ceil() as u32).max(1)
+}
+
+/// Layout of the noise image(s), each split part gets its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CarrierShape {
+    /// Roughly square
+    Square,
+    /// Fixed width in pixels
+    Width(u32),
+    /// Roughly the given width:height ratio
+    Aspect(u32, u32),
+    /// Width of the encrypted image, resolved to `Width` by `encrypt`
+    Original,
+}
+
+impl CarrierShape {
+    /// Width of a noise image holding `len` bytes
+    fn width(self, len: u64) -> Result<u32> {
+        let width = match self {
+            Self::Square => ratio_width(len, 1.0),
+            Self::Width(width) => width,
+            Self::Aspect(w, h) => ratio_width(len, w as f64 / h as f64),
+            Self::Original => bail!(Error::Invalid("The original width is unknown".to_string())),
+        };
+        // A single row of a wider image holds more than an image may
+        if width > MAX_WIDTH {
+            bail!(Error::Invalid(format!("Carrier width {} too large, at most {} pixels", width, MAX_WIDTH)));
+        }
+        Ok(width)
+    }
+}
+
+impl std::str::FromStr for CarrierShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let number = |n: &str| match n.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Invalid carrier size: {}", n)),
+        };
+        let width = |n: &str| match number(n)? {
+            w if w > MAX_WIDTH => Err(format!("Carrier width {} too large, at most {} pixels", w, MAX_WIDTH)),
+            w => Ok(w),
+        };
+        let lower = s.to_ascii_lowercase();
+        match lower.split(':').collect::<Vec<_>>().as_slice() {
+            ["square"] => Ok(Self::Square),
+            ["original"] => Ok(Self::Original),
+            ["width", w] => Ok(Self::Width(width(w)?)),
+            ["aspect", w, h] => Ok(Self::Aspect(number(w)?, number(h)?)),
+            _ => Err(format!("Unknown carrier shape: {} (expected square, original, width:<pixels> or aspect:<w>:<h>)", s)),
+        }
+    }
+}
+
+/// Bytes in each of `num_parts` parts without part headers: all but the last are
+/// `total_len / num_parts` rounded up
+fn part_lens(total_len: u64, num_parts: u64) -> Vec<u64> {
+    let chunk_size = total_len.div_ceil(num_parts);
+    (0..num_parts)
+        .map(|i| total_len.saturating_sub(i * chunk_size).min(chunk_size))
+        .collect()
+}
+
+/// Bytes in each of `num_parts` split parts: the first gets its share rounded up, or
+/// the whole header of `header_len` bytes if that is more, so the header is read from it.
+/// The others share the rest evenly, they only get 0 if there are fewer bytes than parts.
+fn split_lens(total_len: u64, num_parts: u64, header_len: u64) -> Vec<u64> {
+    let first = total_len.div_ceil(num_parts).max(header_len).min(total_len);
+    let (rest, others) = (total_len - first, num_parts - 1);
+    std::iter::once(first)
+        .chain((0..others).map(|i| rest / others + u64::from(i < rest % others)))
+        .collect()
+}
+
+/// Rounds the size of a carrier up, so it doesn't reveal the exact length of the data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Padding {
+    /// Next power of two
+    PowerOfTwo,
+    /// Next multiple of a bucket size in bytes
+    Bucket(u64),
+}
+
+impl Padding {
+    /// Carrier size for `len` bytes of header + ciphertext
+    pub(crate) fn apply(self, len: u64) -> Result<u64> {
+        let padded = match self {
+            Self::PowerOfTwo => len.checked_next_power_of_two(),
+            Self::Bucket(size) => len.div_ceil(size).checked_mul(size),
+        };
+        padded.ok_or_else(|| Error::Invalid(format!("Carrier too large to pad ({} bytes)", len)).into())
+    }
+}
+
+impl std::str::FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        if lower == "pow2" {
+            return Ok(Self::PowerOfTwo);
+        }
+        let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => lower.split_at(i),
+            None => (lower.as_str(), ""),
+        };
+        let unit = match unit {
+            "" => 1,
+            "k" => 1 << 10,
+            "m" => 1 << 20,
+            "g" => 1 << 30,
+            _ => return Err(format!("Unknown padding: {} (expected pow2 or a bucket size like 64K, 1M)", s)),
+        };
+        match number.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+            Some(size) if size > 0 => Ok(Self::Bucket(size)),
+            _ => Err(format!("Invalid bucket size: {}", s)),
+        }
+    }
+}
+
+/// Images for a carrier written to `output_path`: the path itself, or
+/// `splits` images named `<stem>.<n>.<ext>` next to it
+pub(crate) fn part_paths(output_path: &Path, splits: u32) -> Result<Vec<PathBuf>> {
+    if splits <= 1 {
+        return Ok(vec![output_path.to_path_buf()]);
+    }
+    let output_dir = output_path.parent().unwrap_or_else(|| Path::new("."));
+    let output_stem = output_path.file_stem()
+        .ok_or_else(|| Error::Invalid("Invalid output path".to_string()))?
+        .to_str()
+        .ok_or_else(|| Error::Invalid("Invalid Unicode in output path".to_string()))?;
+    let output_ext = output_path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("png");
+    Ok((1..=splits)
+        .map(|i| output_dir.join(format!("{}.{}.{}", output_stem, i, output_ext)))
+        .collect())
+}
+
+/// Split parts of at most `max_part_len` bytes that `capacity` bytes need
+fn parts_needed(capacity: u64, max_part_len: u64) -> Result<u32> {
+    if capacity <= max_part_len {
+        return Ok(1);
+    }
+    u32::try_from(capacity.div_ceil(max_part_len - PART_HEADER_LEN as u64))
+        .map_err(|_| Error::Invalid(format!("Carrier too large ({} bytes)", capacity)).into())
+}
+
+/// Writes the header + ciphertext bytes as one noise image of the given shape,
+/// or as split parts that each start with a part header.
+/// The bytes are streamed into the images. Split parts are all made the same
+/// size, the space after the data is filled with random bytes.
+pub(crate) struct CarrierWriter<'w> {
+    parts: Vec<Part>,
+    sizes: Vec<(u32, u32)>, // width and height of each part
+    index: usize, // part being written
+    written: u64, // bytes written to it
+    sink: Option<PixelWriter<'w>>,
+    coding: Option<Coding<'w>>,
+    outputs: Option<Outputs<'w>>, // writers of the parts, in memory if `None`
+    images: Vec<DynamicImage>, // finished parts of a carrier in memory
+}
+
+/// Makes the writer of a part from its index and the number of parts
+type Outputs<'w> = Box<dyn FnMut(u32, u32) -> io::Result<Box<dyn Write + 'w>> + 'w>;
+
+/// Erasure coded parts, written side by side a stripe at a time
+struct Coding<'w> {
+    erasure: Erasure,
+    stripe: Vec<u8>,
+    left: u64, // bytes not yet written to the stripe
+    sinks: Vec<PixelWriter<'w>>,
+}
+
+impl<'w> CarrierWriter<'w> {
+    /// Carrier written as PNGs to the writers `outputs` makes from the index of
+    /// a part and the number of parts, `splits` of them or more if they don't fit.
+    /// `capacity` is the number of bytes the images hold together, at least `total_len`,
+    /// of which the first `header_len` are the header.
+    /// With `min_parts` below `splits`, the parts are erasure coded and any
+    /// `min_parts` of them are enough to read the carrier.
+    pub fn to_writers<W, F>(total_len: u64, header_len: u64, capacity: u64, shape: CarrierShape, splits: u32, min_parts: Option<u32>, mut outputs: F) -> Result<Self>
+    where
+        W: Write + 'w,
+        F: FnMut(u32, u32) -> io::Result<W> + 'w,
+    {
+        let mut writer = Self::spread(total_len, header_len, capacity, shape, splits, min_parts, MAX_PART_LEN, |count| {
+            Ok((0..count as usize).map(PartImage::Index).collect())
+        })?;
+        writer.outputs = Some(Box::new(move |index, count| Ok(Box::new(outputs(index, count)?))));
+        Ok(writer)
+    }
+
+    /// Carrier written to the given images, in part order
+    pub fn create_at(total_len: u64, header_len: u64, capacity: u64, shape: CarrierShape, paths: Vec<PathBuf>, min_parts: Option<u32>) -> Result<Self> {
+        Self::plan(total_len, header_len, capacity, shape, paths.into_iter().map(PartImage::File).collect(), min_parts, MAX_PART_LEN)
+    }
+
+    /// Carrier kept in memory as `splits` images, or more if it doesn't fit in them, which `finish` returns
+    pub fn in_memory(total_len: u64, header_len: u64, capacity: u64, shape: CarrierShape, splits: u32, min_parts: Option<u32>) -> Result<Self> {
+        Self::spread(total_len, header_len, capacity, shape, splits, min_parts, MAX_PART_LEN, |count| {
+            Ok((0..count as usize).map(PartImage::Index).collect())
+        })
+    }
+
+    /// Carrier in the `splits` images `images` makes, or in as many more as
+    /// images of at most `max_part_len` bytes need. Erasure coded parts aren't spread.
+    #[allow(clippy::too_many_arguments)]
+    fn spread<F>(total_len: u64, header_len: u64, capacity: u64, shape: CarrierShape, splits: u32, min_parts: Option<u32>, max_part_len: u64, images: F) -> Result<Self>
+    where
+        F: FnOnce(u32) -> Result<Vec<PartImage>>,
+    {
+        let capacity = capacity.max(total_len);
+        if min_parts.is_some_and(|k| k < splits) {
+            return Self::plan(total_len, header_len, capacity, shape, images(splits)?, min_parts, max_part_len);
+        }
+        let needed = parts_needed(capacity, max_part_len)?;
+        if needed > splits.max(1) {
+            warn!("{} bytes don't fit in {} image(s), spreading them over {} parts", capacity, splits.max(1), needed);
+        }
+        Self::plan(total_len, header_len, capacity, shape, images(splits.max(needed))?, None, max_part_len)
+    }
+
+    fn plan(total_len: u64, header_len: u64, capacity: u64, shape: CarrierShape, images: Vec<PartImage>, min_parts: Option<u32>, max_part_len: u64) -> Result<Self> {
+        let capacity = capacity.max(total_len);
+        let count = images.len() as u32;
+        let (parts, coding, part_capacity) = match min_parts.filter(|&k| k < count) {
+            Some(k) => {
+                let shard_len = total_len.div_ceil(k as u64).clamp(1, SHARD_LEN as u64) as u32;
+                let erasure = Erasure::new(k, count, shard_len)?;
+                let len = total_len.div_ceil(erasure.stripe_len() as u64) * shard_len as u64;
+                let set_id = new_set_id();
+                debug!("Writing {} parts of set {}, any {} of them hold the carrier", count, set_name(&set_id), k);
+                let parts = images.into_iter()
+                    .enumerate()
+                    .map(|(index, image)| Part {
+                        image,
+                        len,
+                        header: Some(PartHeader { set_id, index: index as u32, count, len, min_parts: k, shard_len }),
+                    })
+                    .collect();
+                let coding = Coding { stripe: Vec::with_capacity(erasure.stripe_len()), erasure, left: total_len, sinks: Vec::new() };
+                (parts, Some(coding), capacity.div_ceil(k as u64))
+            }
+            None => {
+                let mut parts: Vec<Part> = split_lens(total_len, count as u64, header_len).into_iter()
+                    .zip(images)
+                    .filter(|&(len, _)| len > 0)
+                    .map(|(len, image)| Part { image, len, header: None })
+                    .collect();
+                if parts.len() < count as usize {
+                    warn!("{} bytes only fill {} of {} parts, writing {}", total_len, parts.len(), count, parts.len());
+                }
+                if parts.len() > 1 {
+                    let set_id = new_set_id();
+                    let count = parts.len() as u32;
+                    for (index, part) in parts.iter_mut().enumerate() {
+                        part.header = Some(PartHeader { set_id, index: index as u32, count, len: part.len, min_parts: count, shard_len: 0 });
+                    }
+                    debug!("Writing {} parts of set {}", count, set_name(&set_id));
+                }
+                let part_capacity = capacity.div_ceil(parts.len() as u64);
+                (parts, None, part_capacity)
+            }
+        };
+        let sizes = parts.iter()
+            .map(|part| {
+                let len = part.len.max(part_capacity) + (part.image_len() - part.len);
+                if len > max_part_len {
+                    bail!(Error::Invalid(format!("Carrier too large for {} image(s) ({} bytes)", parts.len(), capacity)));
+                }
+                let width = shape.width(len)?;
+                Ok((width, rows(len, width)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        debug!("Carrier shape {:?}, {} of {} bytes used", shape, total_len, capacity);
+        Ok(Self { parts, sizes, index: 0, written: 0, sink: None, coding, outputs: None, images: Vec::new() })
+    }
+
+    /// Write out the last image, fails if fewer bytes were written than planned.
+    /// Returns the images of a carrier in memory, in part order.
+    pub fn finish(mut self) -> Result<Vec<DynamicImage>> {
+        if let Some(coding) = self.coding {
+            if coding.left > 0 || coding.sinks.is_empty() {
+                bail!("Carrier incomplete, {} bytes weren't written", coding.left);
+            }
+            for sink in coding.sinks {
+                self.images.extend(sink.finish()?);
+            }
+            return Ok(self.images);
+        }
+        if self.index < self.parts.len() {
+            bail!("Carrier incomplete, part {} of {} wasn't written", self.index + 1, self.parts.len());
+        }
+        Ok(self.images)
+    }
+
+    /// Create the image of a part and write its part header
+    fn open_part(&mut self, index: usize) -> io::Result<PixelWriter<'w>> {
+        let count = self.parts.len() as u32;
+        let part = &self.parts[index];
+        let (width, height) = self.sizes[index];
+        if count == 1 {
+            info!("Creating single image of dimensions {}x{}", width, height);
+        } else {
+            info!("Saving chunk {} ({}x{}) to {:?}", index + 1, width, height, part.image);
+        }
+        let sink = match (&part.image, self.outputs.as_mut()) {
+            (PartImage::File(path), _) => PixelWriter::create(path, width, height, ColorType::Rgba8),
+            (PartImage::Index(_), Some(outputs)) => PixelWriter::png(outputs(index as u32, count)?, width, height, ColorType::Rgba8),
+            (PartImage::Index(_), None) => PixelWriter::memory(width, height, ColorType::Rgba8),
+        };
+        let mut sink = sink.map_err(io::Error::other)?;
+        if let Some(header) = part.header {
+            sink.write_all(&header.to_bytes())?;
+        }
+        Ok(sink)
+    }
+
+    fn write_coded(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(ref mut coding) = self.coding else {
+            unreachable!("parts aren't erasure coded");
+        };
+        if coding.left == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "More bytes than planned for the carrier"));
+        }
+        let n = (buf.len() as u64)
+            .min(coding.left)
+            .min((coding.erasure.stripe_len() - coding.stripe.len()) as u64) as usize;
+        coding.stripe.extend_from_slice(&buf[..n]);
+        coding.left -= n as u64;
+        if coding.stripe.len() < coding.erasure.stripe_len() && coding.left > 0 {
+            return Ok(n);
+        }
+        // Every part gets one shard of the full stripe
+        let shards = coding.erasure.encode(&coding.stripe).map_err(io::Error::other)?;
+        coding.stripe.clear();
+        if coding.sinks.is_empty() {
+            let sinks = (0..self.parts.len())
+                .map(|index| self.open_part(index))
+                .collect::<io::Result<Vec<_>>>()?;
+            if let Some(ref mut coding) = self.coding {
+                coding.sinks = sinks;
+            }
+        }
+        if let Some(ref mut coding) = self.coding {
+            for (sink, shard) in coding.sinks.iter_mut().zip(shards) {
+                sink.write_all(&shard)?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Write for CarrierWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.coding.is_some() {
+            return self.write_coded(buf);
+        }
+        if self.index >= self.parts.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "More bytes than planned for the carrier"));
+        }
+        if self.sink.is_none() {
+            self.sink = Some(self.open_part(self.index)?);
+        }
+        let (Some(sink), part) = (self.sink.as_mut(), &self.parts[self.index]) else {
+            unreachable!("sink was just opened");
+        };
+        let n = (buf.len() as u64).min(part.len - self.written) as usize;
+        sink.write_all(&buf[..n])?;
+        self.written += n as u64;
+        if self.written == part.len {
+            if let Some(sink) = self.sink.take() {
+                self.images.extend(sink.finish().map_err(io::Error::other)?);
+            }
+            self.index += 1;
+            self.written = 0;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the header + ciphertext bytes back from a noise image, or from split
+/// parts. The header is read on `open`, the ciphertext after it is streamed
+/// part by part, or a stripe at a time from erasure coded parts.
+pub(crate) struct CarrierReader<'r> {
+    header: Header,
+    capacity: u64, // bytes the parts can hold
+    header_bytes: Vec<u8>,
+    width: u32,
+    parts: Vec<Part>, // parts found, in order
+    count: usize, // parts in the set, including missing ones
+    min_parts: Option<u32>, // parts needed if erasure coded
+    index: usize,
+    remaining: u64, // ciphertext bytes left in the current part, or in all parts if erasure coded
+    source: Source<'r>,
+    images: Vec<Option<Unread<'r>>>, // images that aren't files, not read yet
+}
+
+enum Source<'r> {
+    /// Image of the current part
+    Part(PixelReader<'r>),
+    /// Images of the erasure coded parts that are read
+    Stripes(Box<StripeReader<'r>>),
+}
+
+/// Rebuilds the carrier a stripe at a time from the shards of enough erasure coded parts
+struct StripeReader<'r> {
+    erasure: Erasure,
+    readers: Vec<(usize, PixelReader<'r>)>, // part index and image
+    shards: Vec<(Vec<u8>, bool)>, // one per part, `false` if it isn't read
+    stripe: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for StripeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.stripe.len() {
+            for shard in &mut self.shards {
+                shard.1 = false;
+            }
+            for (index, reader) in &mut self.readers {
+                let shard = &mut self.shards[*index];
+                reader.read_exact(&mut shard.0)?;
+                shard.1 = true;
+            }
+            self.erasure.decode(&mut self.shards, &mut self.stripe).map_err(io::Error::other)?;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.stripe.len() - self.pos);
+        buf[..n].copy_from_slice(&self.stripe[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<'r> CarrierReader<'r> {
+    /// `path` is a noise image, one of its split parts, or a directory with the parts
+    pub fn open(path: &Path) -> Result<Self> {
+        let (images, part_headers) = match find_set(path)? {
+            Some(set) => {
+                let (images, headers): (Vec<_>, Vec<_>) = set.into_iter().unzip();
+                (images, Some(headers))
+            }
+            None if path.is_dir() => {
+                info!("Input is a directory, searching for split parts...");
+                (find_parts(path)?.into_iter().map(PartImage::File).collect(), None)
+            }
+            None => {
+                info!("Input is a single file.");
+                (vec![PartImage::File(path.to_path_buf())], None)
+            }
+        };
+        Self::read_parts(images, part_headers, Vec::new())
+    }
+
+    /// `images` are a noise image or the split parts of one, in any order
+    pub fn from_images(images: Vec<DynamicImage>) -> Result<Self> {
+        Self::from_unread(images.into_iter().map(Unread::Image).collect())
+    }
+
+    /// Noise image files read from `readers`, from their position on, in any order
+    pub fn from_readers<R: ImageSource + 'r>(readers: Vec<R>) -> Result<Self> {
+        let images = readers.into_iter()
+            .map(|mut source| Ok(Unread::Reader { start: source.stream_position()?, source: Box::new(source) }))
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_unread(images)
+    }
+
+    fn from_unread(mut images: Vec<Unread<'r>>) -> Result<Self> {
+        if images.is_empty() {
+            bail!(Error::Invalid("No carrier images given".to_string()));
+        }
+        let mut sets: BTreeMap<[u8; 16], Vec<(PartImage, PartHeader)>> = BTreeMap::new();
+        for (index, img) in images.iter_mut().enumerate() {
+            match img.part_header() {
+                Ok(Some(header)) => sets.entry(header.set_id).or_default().push((PartImage::Index(index), header)),
+                Ok(None) => {}
+                Err(e) => debug!("Skipping image {}: {}", index + 1, e),
+            }
+        }
+        let (parts, part_headers) = match pick_set(sets, None, "the images")? {
+            Some(set) => {
+                let (parts, headers): (Vec<_>, Vec<_>) = set.into_iter().unzip();
+                (parts, Some(headers))
+            }
+            // Parts without headers are taken in the order given
+            None => ((0..images.len()).map(PartImage::Index).collect(), None),
+        };
+        Self::read_parts(parts, part_headers, images.into_iter().map(Some).collect())
+    }
+
+    /// Read the header from the first part, or the first stripe of erasure coded parts
+    fn read_parts(images: Vec<PartImage>, part_headers: Option<Vec<PartHeader>>, mut memory: Vec<Option<Unread<'r>>>) -> Result<Self> {
+        if let Some(first) = part_headers.as_ref().map(|h| h[0]).filter(|h| h.coded()) {
+            return Self::open_coded(images, part_headers.unwrap_or_default(), first, memory);
+        }
+
+        let mut source = open_image(&images[0], &mut memory)?;
+        if let Some(ref headers) = part_headers {
+            headers[0].expect(&mut source, &images[0])?;
+        }
+        let (header, header_bytes) = Header::read(&mut source)?;
+        let total_len = header.total_len()?;
+        debug!("Expecting {} bytes based on header in part 1", total_len);
+
+        let parts: Vec<Part> = match part_headers {
+            Some(headers) => {
+                let stored = headers.iter().try_fold(0u64, |sum, h| sum.checked_add(h.len));
+                if stored != Some(total_len) {
+                    bail!(Error::Truncated(format!("The parts of set {} don't hold the {} bytes the header expects", set_name(&headers[0].set_id), total_len)));
+                }
+                headers.into_iter()
+                    .zip(images)
+                    .map(|(header, image)| Part { image, len: header.len, header: Some(header) })
+                    .collect()
+            }
+            None => part_lens(total_len, images.len() as u64).into_iter()
+                .zip(images)
+                .map(|(len, image)| Part { image, len, header: None })
+                .collect(),
+        };
+        if parts[0].len < header_bytes.len() as u64 {
+            bail!(Error::Truncated(format!("First part doesn't contain the whole header: {} < {}", parts[0].len, header_bytes.len())));
+        }
+        check_part(&parts[0], 0, &source)?;
+        let mut capacity = source.byte_len() - (parts[0].image_len() - parts[0].len);
+        for part in &parts[1..] {
+            capacity += image_byte_len(&part.image, &mut memory)?.saturating_sub(part.image_len() - part.len);
+        }
+
+        Ok(Self {
+            remaining: parts[0].len - header_bytes.len() as u64,
+            capacity,
+            width: source.width(),
+            header,
+            header_bytes,
+            count: parts.len(),
+            min_parts: None,
+            parts,
+            index: 0,
+            source: Source::Part(source),
+            images: memory,
+        })
+    }
+
+    /// Erasure coded parts, the first `min_parts` found are read side by side
+    fn open_coded(images: Vec<PartImage>, headers: Vec<PartHeader>, first: PartHeader, mut memory: Vec<Option<Unread<'r>>>) -> Result<Self> {
+        let parts: Vec<Part> = headers.into_iter()
+            .zip(images)
+            .map(|(header, image)| Part { image, len: header.len, header: Some(header) })
+            .collect();
+        let mut capacity = 0;
+        let mut readers = Vec::new();
+        for part in &parts[..first.min_parts as usize] {
+            let Some(header) = part.header else {
+                unreachable!("erasure coded parts have part headers");
+            };
+            let mut source = open_image(&part.image, &mut memory)?;
+            header.expect(&mut source, &part.image)?;
+            check_part(part, header.index as usize, &source)?;
+            capacity += source.byte_len() - header.size() as u64;
+            readers.push((header.index as usize, source));
+        }
+        let width = readers[0].1.width();
+        let shard_len = first.shard_len as usize;
+        let mut source = StripeReader {
+            erasure: Erasure::new(first.min_parts, first.count, first.shard_len)?,
+            readers,
+            shards: vec![(vec![0u8; shard_len], false); first.count as usize],
+            stripe: Vec::new(),
+            pos: 0,
+        };
+        let (header, header_bytes) = Header::read(&mut source)?;
+        let total_len = header.total_len()?;
+        debug!("Expecting {} bytes based on header in stripe 1", total_len);
+        let stripes = total_len.div_ceil(source.erasure.stripe_len() as u64);
+        if stripes.checked_mul(shard_len as u64) != Some(first.len) {
+            bail!(Error::Truncated(format!("The parts of set {} don't hold the {} bytes the header expects", set_name(&first.set_id), total_len)));
+        }
+        Ok(Self {
+            remaining: total_len - header_bytes.len() as u64,
+            capacity,
+            width,
+            header,
+            header_bytes,
+            count: first.count as usize,
+            min_parts: Some(first.min_parts),
+            parts,
+            index: 0,
+            source: Source::Stripes(Box::new(source)),
+            images: memory,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The header as it is stored in the carrier
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.header_bytes
+    }
+
+    /// Width of the first noise image
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Bytes the noise images hold for the header and ciphertext, including
+    /// the padding
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Noise image files found, in part order. A single entry if the input
+    /// wasn't split, none if the images are in memory
+    pub fn parts(&self) -> Vec<PathBuf> {
+        self.parts.iter()
+            .filter_map(|p| match p.image {
+                PartImage::File(ref path) => Some(path.clone()),
+                PartImage::Index(_) => None,
+            })
+            .collect()
+    }
+
+    /// Number of parts the carrier was split into, some may be missing if they are erasure coded
+    pub fn part_count(&self) -> usize {
+        self.count
+    }
+
+    /// Parts needed to read the carrier if they are erasure coded
+    pub fn min_parts(&self) -> Option<u32> {
+        self.min_parts
+    }
+}
+
+fn check_part(part: &Part, index: usize, source: &PixelReader) -> Result<()> {
+    if source.byte_len() < part.image_len() {
+        bail!(Error::Truncated(format!("Part {} too small: {} < {}", index + 1, source.byte_len(), part.image_len())));
+    }
+    Ok(())
+}
+
+impl Read for CarrierReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.index + 1 >= self.parts.len() || self.min_parts.is_some() {
+                return Ok(0);
+            }
+            self.index += 1;
+            let part = &self.parts[self.index];
+            debug!("Reading {} bytes from part {}", part.len, self.index + 1);
+            let mut source = open_image(&part.image, &mut self.images).map_err(io::Error::other)?;
+            if let Some(header) = part.header {
+                header.expect(&mut source, &part.image).map_err(io::Error::other)?;
+            }
+            check_part(part, self.index, &source).map_err(io::Error::other)?;
+            self.remaining = part.len;
+            self.source = Source::Part(source);
+        }
+        let max = (buf.len() as u64).min(self.remaining) as usize;
+        let n = match self.source {
+            Source::Part(ref mut source) => source.read(&mut buf[..max])?,
+            Source::Stripes(ref mut source) => source.read(&mut buf[..max])?,
+        };
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Carrier image ended early"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Part header of an image file, `None` if it isn't a split part
+fn read_part_header(path: &Path) -> Result<Option<PartHeader>> {
+    PartHeader::read(&mut PixelReader::open(path)?)
+}
+
+/// Split parts with part headers, in order: the set `path` is a part of, or
+/// the set in the directory `path`. `None` if there are no part headers.
+fn find_set(path: &Path) -> Result<Option<Vec<(PartImage, PartHeader)>>> {
+    let (dir, own) = if path.is_dir() {
+        (path, None)
+    } else {
+        match read_part_header(path)? {
+            Some(header) => {
+                let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+                (dir, Some(header))
+            }
+            None => return Ok(None),
+        }
+    };
+
+    let mut sets: BTreeMap<[u8; 16], Vec<(PartImage, PartHeader)>> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let p = entry?.path();
+        if !p.is_file() || !is_png_file(&p) {
+            continue;
+        }
+        match read_part_header(&p) {
+            Ok(Some(header)) => sets.entry(header.set_id).or_default().push((PartImage::File(p), header)),
+            Ok(None) => {}
+            Err(e) => debug!("Skipping {:?}: {}", p, e),
+        }
+    }
+    pick_set(sets, own, &format!("{:?}", dir))
+}
+
+/// The set of `own` if given, otherwise the only set or the only one with
+/// enough parts, checked and in order. `place` is where the parts were found.
+fn pick_set(
+    mut sets: BTreeMap<[u8; 16], Vec<(PartImage, PartHeader)>>,
+    own: Option<PartHeader>,
+    place: &str,
+) -> Result<Option<Vec<(PartImage, PartHeader)>>> {
+    let set_id = match own {
+        Some(header) => header.set_id,
+        None => {
+            let complete: Vec<_> = sets.iter()
+                .filter(|(_, parts)| enough_parts(parts))
+                .map(|(id, _)| *id)
+                .collect();
+            match (sets.len(), complete.as_slice()) {
+                (0, _) => return Ok(None),
+                (1, _) => *sets.keys().next().unwrap(),
+                (_, [id]) => *id,
+                (_, []) => {
+                    let sets: Vec<_> = sets.iter()
+                        .map(|(id, parts)| format!("{} is missing part(s) {}", set_name(id), part_numbers(&missing_parts(parts))))
+                        .collect();
+                    bail!(Error::MissingPart(format!("Not enough split parts of any set in {}: {}", place, sets.join("; "))));
+                }
+                _ => {
+                    let names: Vec<_> = complete.iter().map(set_name).collect();
+                    bail!(Error::Invalid(format!("Found {} sets of split parts in {} ({}), give one of their parts instead", complete.len(), place, names.join(", "))));
+                }
+            }
+        }
+    };
+    let mut parts = sets.remove(&set_id).unwrap_or_default();
+    for (id, foreign) in &sets {
+        for (p, header) in foreign {
+            warn!("Ignoring {:?}, it is part {} of {} of another set {}", p, header.index + 1, header.count, set_name(id));
+        }
+    }
+
+    let name = set_name(&set_id);
+    let first = own.unwrap_or_else(|| parts[0].1);
+    let (count, min_parts) = (first.count, first.min_parts);
+    if let Some((p, _)) = parts.iter().find(|(_, h)| (h.count, h.min_parts, h.shard_len) != (count, min_parts, first.shard_len)) {
+        bail!(Error::Invalid(format!("{:?} doesn't match the other parts of set {}", p, name)));
+    }
+    parts.sort_by_key(|(_, h)| h.index);
+    if let Some(pair) = parts.windows(2).find(|pair| pair[0].1.index == pair[1].1.index) {
+        bail!(Error::Invalid(format!("{:?} and {:?} are both part {} of set {}", pair[0].0, pair[1].0, pair[0].1.index + 1, name)));
+    }
+    let missing = missing_parts(&parts);
+    if !enough_parts(&parts) {
+        let needed = if min_parts < count { format!(", {} are needed", min_parts) } else { String::new() };
+        bail!(Error::MissingPart(format!("Missing part(s) {} of {} from set {} in {}{}", part_numbers(&missing), count, name, place, needed)));
+    }
+    if missing.is_empty() {
+        info!("Found {} parts of set {}", count, name);
+    } else {
+        warn!("Missing part(s) {} of {} from set {}, rebuilding the carrier from the other {}", part_numbers(&missing), count, name, parts.len());
+    }
+    Ok(Some(parts))
+}
+
+/// Whether the parts of one set are enough to read it, all of them unless they are erasure coded
+fn enough_parts(parts: &[(PartImage, PartHeader)]) -> bool {
+    let Some((_, first)) = parts.first() else {
+        return false;
+    };
+    first.count as usize - missing_parts(parts).len() >= first.min_parts as usize
+}
+
+/// Indices of the parts not in `parts`, all from one set
+fn missing_parts(parts: &[(PartImage, PartHeader)]) -> Vec<u32> {
+    let count = parts.first().map_or(0, |(_, h)| h.count);
+    (0..count)
+        .filter(|&i| !parts.iter().any(|(_, h)| h.index == i))
+        .collect()
+}
+
+/// Part indices as the numbers shown to the user, counting from 1
+fn part_numbers(indices: &[u32]) -> String {
+    indices.iter().map(|i| (i + 1).to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Split parts without part headers in a directory, sorted by the number in
+/// `<stem>.<n>.png`, as written before parts had headers
+fn find_parts(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files_with_parts: Vec<(u32, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let p = entry.path();
+        if p.is_file() {
+            if let Some(part_num) = p.file_name().and_then(|f| f.to_str()).and_then(part_number) {
+                files_with_parts.push((part_num, p.clone()));
+            }
+        }
+    }
+
+    if files_with_parts.is_empty() {
+        bail!(Error::MissingPart(format!("No split image parts found in the directory: {:?}", path)));
+    }
+
+    // Sort by part number
+    files_with_parts.sort_by_key(|k| k.0);
+    Ok(files_with_parts.into_iter().map(|(_, p)| p).collect())
+}
+
+/// The `<n>` of a `<stem>.<n>.png` file name
+fn part_number(name: &str) -> Option<u32> {
+    let rest = name.strip_suffix(".png").or_else(|| name.strip_suffix(".PNG"))?;
+    let (_, number) = rest.rsplit_once('.')?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CipherSuite;
+
+    /// Header for `len` ciphertext bytes followed by random ones
+    fn carrier_bytes(len: u64) -> Vec<u8> {
+        let header = Header::new(CipherSuite::default(), vec![0u8; 12], 0, 0, len, Vec::new());
+        let mut bytes = header.to_bytes().unwrap();
+        let start = bytes.len();
+        bytes.resize(start + len as usize, 0);
+        fill_random(&mut bytes[start..]);
+        bytes
+    }
+
+    fn memory(count: u32) -> Result<Vec<PartImage>> {
+        Ok((0..count as usize).map(PartImage::Index).collect())
+    }
+
+    #[test]
+    fn parts_needed_leaves_room_for_part_headers() {
+        assert_eq!(parts_needed(4096, 4096).unwrap(), 1);
+        assert_eq!(parts_needed(4097, 4096).unwrap(), 2);
+        assert_eq!(parts_needed(2 * (4096 - PART_HEADER_LEN as u64) + 1, 4096).unwrap(), 3);
+        assert_eq!(parts_needed(MAX_PART_LEN + 1, MAX_PART_LEN).unwrap(), 2);
+        assert!(parts_needed(u64::MAX, 4096).is_err());
+    }
+
+    #[test]
+    fn large_carrier_is_spread_and_read_back() {
+        let bytes = carrier_bytes(20_000);
+        let mut carrier = CarrierWriter::spread(bytes.len() as u64, 0, 0, CarrierShape::Square, 1, None, 4096, memory).unwrap();
+        carrier.write_all(&bytes).unwrap();
+        let images = carrier.finish().unwrap();
+        assert_eq!(images.len() as u32, parts_needed(bytes.len() as u64, 4096).unwrap());
+        assert!(images.iter().all(|img| img.width() as u64 * img.height() as u64 * 4 <= 4096));
+
+        // The parts record their order
+        let mut reader = CarrierReader::from_images(images.into_iter().rev().collect()).unwrap();
+        let mut read = reader.header_bytes().to_vec();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+    }
+
+    #[test]
+    fn padded_carrier_is_spread_by_capacity() {
+        let bytes = carrier_bytes(1_000);
+        let carrier = CarrierWriter::spread(bytes.len() as u64, 0, 10_000, CarrierShape::Square, 2, None, 4096, memory).unwrap();
+        assert_eq!(carrier.parts.len(), 3);
+    }
+
+    #[test]
+    fn part_larger_than_the_limit_fails() {
+        assert!(CarrierWriter::plan(5_000, 0, 0, CarrierShape::Square, memory(1).unwrap(), None, 4096).is_err());
+        assert!(CarrierWriter::plan(5_000, 0, 0, CarrierShape::Square, memory(2).unwrap(), None, 4096).is_ok());
+    }
+
+    #[test]
+    fn first_part_holds_the_header() {
+        assert_eq!(part_lens(10, 3), [4, 4, 2]);
+        assert_eq!(split_lens(10, 3, 0), [4, 3, 3]);
+        assert_eq!(split_lens(10, 3, 7), [7, 2, 1]);
+        assert_eq!(split_lens(10, 3, 9), [9, 1, 0]);
+        assert_eq!(split_lens(10, 1, 7), [10]);
+
+        let bytes = carrier_bytes(40);
+        let header_len = bytes.len() as u64 - 40;
+        let mut carrier = CarrierWriter::spread(bytes.len() as u64, header_len, 0, CarrierShape::Square, 3, None, 4096, memory).unwrap();
+        carrier.write_all(&bytes).unwrap();
+        let images = carrier.finish().unwrap();
+        assert_eq!(images.len(), 3);
+        let mut reader = CarrierReader::from_images(images).unwrap();
+        let mut read = reader.header_bytes().to_vec();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+    }
+
+    #[test]
+    fn parts_without_bytes_are_dropped() {
+        let carrier = CarrierWriter::plan(10, 9, 0, CarrierShape::Square, memory(3).unwrap(), None, 4096).unwrap();
+        assert_eq!(carrier.parts.iter().map(|p| p.len).collect::<Vec<_>>(), [9, 1]);
+        assert!(carrier.parts.iter().all(|p| p.header.is_some_and(|h| h.count == 2)));
+
+        // A single part left is a plain image without a part header
+        let carrier = CarrierWriter::plan(10, 10, 0, CarrierShape::Square, memory(3).unwrap(), None, 4096).unwrap();
+        assert_eq!(carrier.parts.len(), 1);
+        assert!(carrier.parts[0].header.is_none());
+    }
+}
@@ -1,4 +1,15 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use crate::carrier::{
+    CarrierShape,
+    Padding
+};
+use crate::compress::Compression;
+use crate::crypto::{
+    CipherSuite,
+    KdfAlgorithm,
+    KdfParams,
+    KDF_OUTPUT_LEN
+};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -26,6 +37,14 @@ pub enum Commands {
         #[arg(short = 'n', long, default_value = "2")]
         num_parts: u32,
 
+        /// Parts needed to merge (Shamir sharing), all of them by default
+        #[arg(short = 'k', long)]
+        threshold: Option<u32>,
+
+        /// Share the alpha channel too, so transparency survives a merge. The parts get random transparency
+        #[arg(long)]
+        alpha: bool,
+
         /// Delete the original fil
         #[arg(short = 'd', long)]
         delete_original: bool,
@@ -39,6 +58,10 @@ pub enum Commands {
         /// Output file path
         #[arg(short = 'o', long)]
         output: String,
+
+        /// The parts were split with --alpha (Shamir shares record this themselves)
+        #[arg(long)]
+        alpha: bool,
     },
 
     Encrypt {
@@ -57,10 +80,63 @@ pub enum Commands {
         /// File to use as a password/key
         #[arg(long, group = "auth_method")]
         password_file: Option<String>,
+
+        /// Environment variable holding the password
+        #[arg(long, value_name = "VAR", group = "auth_method")]
+        password_env: Option<String>,
+
+        /// Shell command that prints the password
+        #[arg(long, value_name = "COMMAND", group = "auth_method")]
+        password_command: Option<String>,
     
         /// Number of blocks
         #[arg(short = 's', long, default_value = "1")]
         split: u32,
+
+        /// Erasure code the blocks so that any this many of them are enough to decrypt
+        #[arg(long, value_name = "K")]
+        min_parts: Option<u32>,
+
+        /// Public key file of a recipient, can be given several times
+        #[arg(short = 'r', long)]
+        recipient: Vec<String>,
+
+        /// Ed25519 private key file to sign the image with
+        #[arg(long)]
+        sign_key: Option<String>,
+
+        /// Cipher (aes-256-gcm, aes-256-gcm-siv, xchacha20-poly1305)
+        #[arg(long, default_value = "aes-256-gcm")]
+        cipher: CipherSuite,
+
+        /// Number of threads, one per core by default
+        #[arg(long)]
+        threads: Option<usize>,
+
+        /// Encrypt the file as it is instead of its pixels, for any kind of file.
+        /// Files that aren't images are always encrypted this way
+        #[arg(long)]
+        raw: bool,
+
+        /// Encrypt the image file byte for byte and keep its format, so decrypting
+        /// gives back the identical file with all of its metadata
+        #[arg(long, conflicts_with = "raw")]
+        lossless: bool,
+
+        /// Compress the data before encrypting it: zstd or deflate, with an optional level (zstd:19, deflate:9)
+        #[arg(long, value_name = "CODEC[:LEVEL]")]
+        compress: Option<Compression>,
+
+        /// Layout of the noise image(s): square (default), original, width:<pixels> or aspect:<w>:<h>
+        #[arg(long, value_name = "SHAPE")]
+        carrier_shape: Option<CarrierShape>,
+
+        /// Round the noise image size up to hide the data length: pow2 or a bucket size like 64K, 1M
+        #[arg(long, value_name = "BUCKETS")]
+        pad: Option<Padding>,
+
+        #[command(flatten)]
+        kdf: KdfArgs,
     },
 
     Decrypt {
@@ -79,5 +155,218 @@ pub enum Commands {
         /// File used as the password/key
         #[arg(long, group = "auth_method")]
         password_file: Option<String>,
+
+        /// Environment variable holding the password
+        #[arg(long, value_name = "VAR", group = "auth_method")]
+        password_env: Option<String>,
+
+        /// Shell command that prints the password
+        #[arg(long, value_name = "COMMAND", group = "auth_method")]
+        password_command: Option<String>,
+
+        /// Private key file, for images encrypted to a recipient
+        #[arg(long, group = "auth_method")]
+        identity: Option<String>,
+
+        /// Number of threads, one per core by default
+        #[arg(long)]
+        threads: Option<usize>,
     },
+
+    /// Generate an X25519 key pair for recipients
+    Keygen {
+        /// Output path, writes <OUTPUT>.key and <OUTPUT>.pub
+        #[arg(short = 'o', long)]
+        output: String,
+
+        /// Generate an Ed25519 key pair for signing instead
+        #[arg(short = 's', long)]
+        signing: bool,
+    },
+
+    /// Check the signature of an encrypted image, no password needed
+    Verify {
+        /// Input File or Dir
+        #[arg(short = 'i', long)]
+        input: String,
+
+        /// Public key file of the expected signer
+        #[arg(long)]
+        signer: Option<String>,
+    },
+
+    /// Change the password/key file without re-encrypting the image
+    Rekey {
+        /// Input File or Dir
+        #[arg(short = 'i', long)]
+        input: String,
+
+        /// Output file, overwrites the input if not given
+        #[arg(short = 'o', long)]
+        output: Option<String>,
+
+        /// Current pwd
+        #[arg(short = 'p', long, group = "auth_method")]
+        password: Option<String>,
+
+        /// Current password/key file
+        #[arg(long, group = "auth_method")]
+        password_file: Option<String>,
+
+        /// Environment variable holding the current password
+        #[arg(long, value_name = "VAR", group = "auth_method")]
+        password_env: Option<String>,
+
+        /// Shell command that prints the current password
+        #[arg(long, value_name = "COMMAND", group = "auth_method")]
+        password_command: Option<String>,
+
+        /// New pwd
+        #[arg(long, group = "new_auth_method")]
+        new_password: Option<String>,
+
+        /// New password/key file
+        #[arg(long, group = "new_auth_method")]
+        new_password_file: Option<String>,
+
+        /// Environment variable holding the new password
+        #[arg(long, value_name = "VAR", group = "new_auth_method")]
+        new_password_env: Option<String>,
+
+        /// Shell command that prints the new password
+        #[arg(long, value_name = "COMMAND", group = "new_auth_method")]
+        new_password_command: Option<String>,
+
+        #[command(flatten)]
+        kdf: KdfArgs,
+    },
+
+    /// Manage the passwords/key files of an encrypted image
+    Slot {
+        #[command(subcommand)]
+        command: SlotCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SlotCommands {
+    /// Add a password/key file
+    Add {
+        /// Input File or Dir
+        #[arg(short = 'i', long)]
+        input: String,
+
+        /// Output file, overwrites the input if not given
+        #[arg(short = 'o', long)]
+        output: Option<String>,
+
+        /// Existing pwd
+        #[arg(short = 'p', long, group = "auth_method")]
+        password: Option<String>,
+
+        /// Existing password/key file
+        #[arg(long, group = "auth_method")]
+        password_file: Option<String>,
+
+        /// Environment variable holding the existing password
+        #[arg(long, value_name = "VAR", group = "auth_method")]
+        password_env: Option<String>,
+
+        /// Shell command that prints the existing password
+        #[arg(long, value_name = "COMMAND", group = "auth_method")]
+        password_command: Option<String>,
+
+        /// Pwd to add
+        #[arg(long, group = "new_auth_method")]
+        new_password: Option<String>,
+
+        /// Password/key file to add
+        #[arg(long, group = "new_auth_method")]
+        new_password_file: Option<String>,
+
+        /// Environment variable holding the password to add
+        #[arg(long, value_name = "VAR", group = "new_auth_method")]
+        new_password_env: Option<String>,
+
+        /// Shell command that prints the password to add
+        #[arg(long, value_name = "COMMAND", group = "new_auth_method")]
+        new_password_command: Option<String>,
+
+        /// Public key file of a recipient to add
+        #[arg(long, group = "new_auth_method")]
+        new_recipient: Option<String>,
+
+        #[command(flatten)]
+        kdf: KdfArgs,
+    },
+
+    /// Remove a password/key file
+    Remove {
+        /// Input File or Dir
+        #[arg(short = 'i', long)]
+        input: String,
+
+        /// Output file, overwrites the input if not given
+        #[arg(short = 'o', long)]
+        output: Option<String>,
+
+        /// Existing pwd
+        #[arg(short = 'p', long, group = "auth_method")]
+        password: Option<String>,
+
+        /// Existing password/key file
+        #[arg(long, group = "auth_method")]
+        password_file: Option<String>,
+
+        /// Environment variable holding the existing password
+        #[arg(long, value_name = "VAR", group = "auth_method")]
+        password_env: Option<String>,
+
+        /// Shell command that prints the existing password
+        #[arg(long, value_name = "COMMAND", group = "auth_method")]
+        password_command: Option<String>,
+
+        /// Slot to remove, defaults to the one the password/key file unlocks
+        #[arg(short = 's', long)]
+        slot: Option<usize>,
+    },
+
+    /// List the key slots
+    List {
+        /// Input File or Dir
+        #[arg(short = 'i', long)]
+        input: String,
+    },
+}
+
+/// Argon2 parameters for new password key slots
+#[derive(Args, Debug)]
+pub struct KdfArgs {
+    /// Argon2 variant for password keys (argon2d, argon2i, argon2id)
+    #[arg(long, default_value = "argon2id")]
+    pub kdf_algorithm: KdfAlgorithm,
+
+    /// Argon2 memory cost in KiB
+    #[arg(long, default_value = "19456")]
+    pub kdf_m_cost: u32,
+
+    /// Argon2 number of iterations
+    #[arg(long, default_value = "2")]
+    pub kdf_t_cost: u32,
+
+    /// Argon2 degree of parallelism
+    #[arg(long, default_value = "1")]
+    pub kdf_parallelism: u32,
+}
+
+impl KdfArgs {
+    pub fn params(&self) -> KdfParams {
+        KdfParams {
+            algorithm: self.kdf_algorithm,
+            m_cost: self.kdf_m_cost,
+            t_cost: self.kdf_t_cost,
+            p_cost: self.kdf_parallelism,
+            output_len: KDF_OUTPUT_LEN,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use anyhow::{
+    Result,
+    bail
+};
+use crate::error::{
+    self,
+    Error
+};
+use std::io::{
+    self,
+    Read,
+    Write
+};
+
+/// Compression codec applied to the payload before it is encrypted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd = 1,
+    Deflate = 2,
+}
+
+impl Codec {
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Deflate),
+            _ => Err(Error::UnsupportedVersion(format!("Unknown compression codec id {}", id)).into()),
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Level used when `--compress` doesn't give one
+    pub fn default_level(self) -> i32 {
+        match self {
+            Self::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Self::Deflate => 6,
+        }
+    }
+
+    fn levels(self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Self::Zstd => zstd::compression_level_range(),
+            Self::Deflate => 0..=9,
+        }
+    }
+}
+
+/// Codec and level, written as `<codec>[:level]` on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+impl Compression {
+    pub fn new(codec: Codec, level: i32) -> error::Result<Self> {
+        if !codec.levels().contains(&level) {
+            return Err(Error::Invalid(format!("Invalid {:?} level {} (expected {:?})", codec, level, codec.levels())));
+        }
+        Ok(Self { codec, level })
+    }
+
+    /// Reader returning the compressed bytes of `reader`.
+    /// The output only depends on the input, so it can be read more than once.
+    pub(crate) fn encoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self.codec {
+            Codec::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, self.level)?),
+            Codec::Deflate => Box::new(flate2::read::DeflateEncoder::new(reader, flate2::Compression::new(self.level as u32))),
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        let codec = match name.to_ascii_lowercase().as_str() {
+            "zstd" => Codec::Zstd,
+            "deflate" => Codec::Deflate,
+            _ => return Err(format!("Unknown compression: {} (expected zstd or deflate)", name)),
+        };
+        let level = match level {
+            Some(level) => level.parse().map_err(|_| format!("Invalid compression level: {}", level))?,
+            None => codec.default_level(),
+        };
+        Self::new(codec, level).map_err(|e| e.to_string())
+    }
+}
+
+/// Writer decompressing into `writer`, which must get exactly `size` bytes
+pub(crate) struct Decompressor<W: Write> {
+    decoder: Decoder<W>,
+    size: u64,
+}
+
+enum Decoder<W: Write> {
+    Zstd(zstd::stream::write::Decoder<'static, Limit<W>>),
+    Deflate(flate2::write::DeflateDecoder<Limit<W>>),
+}
+
+impl<W: Write> Decompressor<W> {
+    pub fn new(codec: Codec, writer: W, size: u64) -> Result<Self> {
+        let writer = Limit { inner: writer, left: size };
+        let decoder = match codec {
+            Codec::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(writer)?),
+            Codec::Deflate => Decoder::Deflate(flate2::write::DeflateDecoder::new(writer)),
+        };
+        Ok(Self { decoder, size })
+    }
+
+    /// Write out the rest and return the inner writer
+    pub fn finish(self) -> Result<W> {
+        let writer = match self.decoder {
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            }
+            Decoder::Deflate(decoder) => decoder.finish()?,
+        };
+        if writer.left != 0 {
+            bail!("Decompressed {} of {} bytes", self.size - writer.left, self.size);
+        }
+        Ok(writer.inner)
+    }
+}
+
+impl<W: Write> Write for Decompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.decoder {
+            Decoder::Zstd(ref mut decoder) => decoder.write(buf),
+            Decoder::Deflate(ref mut decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.decoder {
+            Decoder::Zstd(ref mut decoder) => decoder.flush(),
+            Decoder::Deflate(ref mut decoder) => decoder.flush(),
+        }
+    }
+}
+
+/// Fails instead of writing more than `left` bytes, so a payload can't
+/// decompress to more than the header announces
+struct Limit<W: Write> {
+    inner: W,
+    left: u64,
+}
+
+impl<W: Write> Write for Limit<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.left {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Payload decompresses to more than its size"));
+        }
+        let n = self.inner.write(buf)?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
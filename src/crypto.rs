@@ -1,6 +1,7 @@
 use anyhow::{
     Result,
-    anyhow
+    anyhow,
+    bail
 };
 use aes_gcm::{
     Aes256Gcm,
@@ -10,27 +11,196 @@ use aes_gcm::{
 use aes_gcm::aead::{
     Aead,
     KeyInit,
-    OsRng
+    OsRng,
+    Payload
 };
+use aes_gcm::aead::generic_array::GenericArray;
 use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::XChaCha20Poly1305;
 use argon2::{
+    Algorithm,
     Argon2,
-    PasswordHasher
+    Params,
+    Version
 };
-use argon2::password_hash::SaltString;
 use sha2::{
     Sha256,
+    Sha512,
     Digest
 };
+use ed25519_dalek::{
+    Signer,
+    SigningKey,
+    Verifier,
+    VerifyingKey
+};
+use hkdf::Hkdf;
+use x25519_dalek::{
+    PublicKey,
+    StaticSecret
+};
 use log::{
     info,
     debug,
     warn
 };
+use crate::error::{
+    self,
+    Error
+};
+use crate::header::{
+    KeySlot,
+    Signature
+};
+use std::env;
 use std::fs;
+use std::io;
+use std::process;
 use rand::RngCore as OldRngCore;
 
-pub fn pwd2key(password: &str, salt_opt: Option<[u8; 16]>) -> Result<([u8; 32], [u8; 16])> {
+/// Argon2 output length, the key is always 32 bytes
+pub const KDF_OUTPUT_LEN: u32 = 32;
+
+/// Most memory a key slot may ask Argon2 for, 4 GiB in KiB
+pub const MAX_KDF_M_COST: u32 = 4 * 1024 * 1024;
+
+/// Most Argon2 iterations a key slot may ask for
+pub const MAX_KDF_T_COST: u32 = 64;
+
+/// Most Argon2 lanes a key slot may ask for
+pub const MAX_KDF_P_COST: u32 = 64;
+
+/// Environment variable with a lower memory cap (in KiB) for opening key slots
+pub const KDF_M_COST_LIMIT_VAR: &str = "P2W_MAX_KDF_M_COST";
+
+/// Environment variable with a lower cap on the iterations for opening key slots
+pub const KDF_T_COST_LIMIT_VAR: &str = "P2W_MAX_KDF_T_COST";
+
+/// Environment variable with a lower cap on the lanes for opening key slots
+pub const KDF_P_COST_LIMIT_VAR: &str = "P2W_MAX_KDF_P_COST";
+
+/// Argon2 parameters used to derive a key from a password
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    /// Always `KDF_OUTPUT_LEN`, the key is 32 bytes. Stored in the header for older images
+    pub output_len: u32,
+}
+
+/// Argon2 variant, the ids match the ones used by the argon2 crate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KdfAlgorithm {
+    Argon2d = 0,
+    Argon2i = 1,
+    Argon2id = 2,
+}
+
+impl KdfAlgorithm {
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::Argon2d),
+            1 => Ok(Self::Argon2i),
+            2 => Ok(Self::Argon2id),
+            _ => Err(Error::UnsupportedVersion(format!("Unknown KDF algorithm id {}", id)).into()),
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+impl std::str::FromStr for KdfAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "argon2d" => Ok(Self::Argon2d),
+            "argon2i" => Ok(Self::Argon2i),
+            "argon2id" => Ok(Self::Argon2id),
+            _ => Err(format!("Unknown KDF algorithm: {} (expected argon2d, argon2i or argon2id)", s)),
+        }
+    }
+}
+
+/// Same as `Argon2::default()`, which is what v1 files were written with
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            output_len: Params::DEFAULT_OUTPUT_LEN as u32,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Check the params before spending time on hashing.
+    /// Also used on the params read from a header, so they can't make us allocate without bound
+    pub fn validate(&self) -> error::Result<()> {
+        if self.output_len != KDF_OUTPUT_LEN {
+            return Err(Error::Invalid(format!("KDF output length must be {} bytes (got {})", KDF_OUTPUT_LEN, self.output_len)));
+        }
+        if self.m_cost > MAX_KDF_M_COST {
+            return Err(Error::Invalid(format!("KDF memory cost {} KiB too large, at most {} KiB", self.m_cost, MAX_KDF_M_COST)));
+        }
+        if self.t_cost > MAX_KDF_T_COST {
+            return Err(Error::Invalid(format!("KDF time cost {} too large, at most {} iterations", self.t_cost, MAX_KDF_T_COST)));
+        }
+        if self.p_cost > MAX_KDF_P_COST {
+            return Err(Error::Invalid(format!("KDF parallelism {} too large, at most {} lanes", self.p_cost, MAX_KDF_P_COST)));
+        }
+        Params::new(self.m_cost, self.t_cost, self.p_cost, Some(self.output_len as usize))
+            .map_err(|e| Error::Invalid(format!("Invalid Argon2 parameters: {}", e)))?;
+        Ok(())
+    }
+
+    /// Refuse slots that need more memory, iterations or lanes than
+    /// `P2W_MAX_KDF_M_COST`, `P2W_MAX_KDF_T_COST` and `P2W_MAX_KDF_P_COST` allow
+    pub(crate) fn check_limit(&self) -> error::Result<()> {
+        let limit = kdf_limit(KDF_M_COST_LIMIT_VAR, MAX_KDF_M_COST, "a number of KiB")?;
+        if self.m_cost > limit {
+            return Err(Error::Invalid(format!("Key slot needs {} KiB of memory for Argon2, more than the limit of {} KiB set by {}", self.m_cost, limit, KDF_M_COST_LIMIT_VAR)));
+        }
+        let limit = kdf_limit(KDF_T_COST_LIMIT_VAR, MAX_KDF_T_COST, "a number of iterations")?;
+        if self.t_cost > limit {
+            return Err(Error::Invalid(format!("Key slot needs {} Argon2 iterations, more than the limit of {} set by {}", self.t_cost, limit, KDF_T_COST_LIMIT_VAR)));
+        }
+        let limit = kdf_limit(KDF_P_COST_LIMIT_VAR, MAX_KDF_P_COST, "a number of lanes")?;
+        if self.p_cost > limit {
+            return Err(Error::Invalid(format!("Key slot needs {} Argon2 lanes, more than the limit of {} set by {}", self.p_cost, limit, KDF_P_COST_LIMIT_VAR)));
+        }
+        Ok(())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(self.output_len as usize))
+            .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+            KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+            KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+        };
+        Ok(Argon2::new(algorithm, Version::V0x13, params))
+    }
+}
+
+/// Limit from the environment variable `var`, `max` if it isn't set
+fn kdf_limit(var: &str, max: u32, unit: &str) -> error::Result<u32> {
+    match env::var(var) {
+        Ok(v) => v.trim().parse::<u32>()
+            .map_err(|_| Error::Invalid(format!("{} must be {} (got {:?})", var, unit, v))),
+        Err(_) => Ok(max),
+    }
+}
+
+pub(crate) fn pwd2key(password: &str, salt_opt: Option<[u8; 16]>, params: &KdfParams) -> Result<([u8; 32], [u8; 16])> {
     info!("Generating key from password string using Argon2");
     let salt = match salt_opt {
         Some(s) => s,
@@ -40,22 +210,21 @@ pub fn pwd2key(password: &str, salt_opt: Option<[u8; 16]>) -> Result<([u8; 32],
             s
         }
     };
-    debug!("Using salt: {:?}", hex::encode(&salt));
-    let argon = Argon2::default();
-    let salt_str = SaltString::encode_b64(&salt).unwrap();
-    let hash = argon.hash_password(password.as_bytes(), &salt_str)
+    debug!("Using salt: {:?}", hex::encode(salt));
+    debug!("Using KDF params: {:?}", params);
+    let argon = params.argon2()?;
+    let mut hash_bytes = vec![0u8; params.output_len as usize];
+    argon.hash_password_into(password.as_bytes(), &salt, &mut hash_bytes)
         .map_err(|e| anyhow!("Argon2 hashing failed: {}", e))?;
     let mut key = [0u8; 32];
-    let binding = hash.hash.unwrap();
-    let hash_bytes = binding.as_bytes();
     let len = hash_bytes.len().min(32);
     key[..len].copy_from_slice(&hash_bytes[..len]);
-    debug!("Generated key: {:?}", hex::encode(&key));
+    debug!("Generated key: {:?}", hex::encode(key));
     Ok((key, salt))
 }
 
 /// Hash the file using SHA256
-pub fn file2key(path: &str) -> Result<([u8; 32], [u8; 16])> {
+pub(crate) fn file2key(path: &str) -> Result<([u8; 32], [u8; 16])> {
     info!("Hash key from file: {}", path);
     let data = fs::read(path)?;
     let mut hasher = Sha256::new(); // Du dumm? rust analyser
@@ -63,62 +232,494 @@ pub fn file2key(path: &str) -> Result<([u8; 32], [u8; 16])> {
     let result = hasher.finalize();
     let mut key = [0u8; 32];
     key.copy_from_slice(&result[..]);
-    debug!("Hash key from file: {:?}", hex::encode(&key));
+    debug!("Hash key from file: {:?}", hex::encode(key));
     Ok((key, [0u8; 16]))
 }
 
 /// Generate a rand nonce for AES-GCM
-pub fn generate_nonce() -> [u8; 12] {
+pub(crate) fn generate_nonce() -> [u8; 12] {
     let mut nonce = [0u8; 12];
     OsRng.fill_bytes(&mut nonce);
-    debug!("Random nonce: {:?}", hex::encode(&nonce));
+    debug!("Random nonce: {:?}", hex::encode(nonce));
     nonce
 }
 
 /// Selfcheck token
-pub fn generate_token(key: &[u8; 32]) -> [u8; 32] {
+pub(crate) fn generate_token(key: &[u8; 32]) -> [u8; 32] {
     let mut hasher = Sha256::new(); // bist du dumm? rust analyzer, hör auf!
     hasher.update(key);
     let result = hasher.finalize();
     let mut token = [0u8; 32];
     token.copy_from_slice(&result[..]);
-    debug!("Selfcheck token: {:?}", hex::encode(&token));
+    debug!("Selfcheck token: {:?}", hex::encode(token));
     token
 }
 
-/// Encrypt with AES-GCM
-pub fn encrypt(key: &[u8; 32], nonce_bytes: &[u8; 12], plaintext: &[u8]) -> Result<(Vec<u8>, [u8; 16])> {
-    let key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
-    let nonce = Nonce::from_slice(nonce_bytes);
+/// AEAD used for the image data, stored in the header
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm = 1,
+    Aes256GcmSiv = 2,
+    XChaCha20Poly1305 = 3,
+}
+
+impl CipherSuite {
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Self::Aes256Gcm),
+            2 => Ok(Self::Aes256GcmSiv),
+            3 => Ok(Self::XChaCha20Poly1305),
+            _ => Err(Error::UnsupportedVersion(format!("Unknown cipher suite id {}", id)).into()),
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Self::Aes256Gcm | Self::Aes256GcmSiv => 12,
+            Self::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Random nonce of the right length for this suite
+    pub fn generate_nonce(self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        OsRng.fill_bytes(&mut nonce);
+        debug!("Random nonce: {:?}", hex::encode(&nonce));
+        nonce
+    }
+
+    /// Encrypt, `aad` is authenticated but not encrypted
+    pub(crate) fn encrypt(self, key: &[u8; 32], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, [u8; 16])> {
+        let mut ciphertext = self.seal(key, nonce, plaintext, aad)?;
+        // Split the ciphertext and the auth tag
+        let auth_tag = ciphertext.split_off(ciphertext.len() - 16);
+        let mut auth_tag_array = [0u8; 16];
+        auth_tag_array.copy_from_slice(&auth_tag);
 
-    debug!("Encrypting {} bytes", plaintext.len());
-    let ciphertext_with_tag = cipher.encrypt(nonce, plaintext)
+        info!("Encryption successful, length {}", ciphertext.len());
+        Ok((ciphertext, auth_tag_array))
+    }
+
+    /// Decrypt, `aad` must match the one used for encryption
+    pub(crate) fn decrypt(self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], auth_tag: &[u8; 16], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut ciphertext_with_tag = Vec::with_capacity(ciphertext.len() + 16);
+        ciphertext_with_tag.extend_from_slice(ciphertext);
+        ciphertext_with_tag.extend_from_slice(auth_tag);
+        let plaintext = self.open(key, nonce, &ciphertext_with_tag, aad)?;
+        info!("Decryption successful, length {}", plaintext.len());
+        Ok(plaintext)
+    }
+
+    /// Encrypt, returns the ciphertext with the auth tag appended
+    pub(crate) fn seal(self, key: &[u8; 32], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.check_nonce(nonce)?;
+        debug!("Encrypting {} bytes with {:?}", plaintext.len(), self);
+        match self {
+            Self::Aes256Gcm => aead_seal::<Aes256Gcm>(key, nonce, plaintext, aad),
+            Self::Aes256GcmSiv => aead_seal::<Aes256GcmSiv>(key, nonce, plaintext, aad),
+            Self::XChaCha20Poly1305 => aead_seal::<XChaCha20Poly1305>(key, nonce, plaintext, aad),
+        }
         .map_err(|e| {
-            warn!("AES-GCM encryption failed: {:?}", e);
-            anyhow::anyhow!("AES-GCM encryption failed: {:?}", e)
-        })?;
-    // Split the ciphertext and the auth tag
-    let (ciphertext, auth_tag) = ciphertext_with_tag.split_at(ciphertext_with_tag.len() - 16);
-    let mut auth_tag_array = [0u8; 16];
-    auth_tag_array.copy_from_slice(auth_tag);
-    
-    info!("Encryption successful, length {}", ciphertext.len());
-    Ok((ciphertext.to_vec(), auth_tag_array))
-}
-
-/// Decrypt mit AES-GCM
-pub fn decrypt(key: &[u8; 32], nonce_bytes: &[u8; 12], ciphertext: &[u8], auth_tag: &[u8; 16]) -> Result<Vec<u8>> {
-    let key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    let mut ciphertext_with_tag = Vec::from(ciphertext);
-    ciphertext_with_tag.extend_from_slice(auth_tag);
-    debug!("Decrypting {} bytes", ciphertext.len());
-    let plaintext = cipher.decrypt(nonce, ciphertext_with_tag.as_ref())
+            warn!("{:?} encryption failed: {:?}", self, e);
+            anyhow!("{:?} encryption failed: {:?}", self, e)
+        })
+    }
+
+    /// Decrypt a ciphertext with the auth tag appended, as returned by `seal`
+    pub(crate) fn open(self, key: &[u8; 32], nonce: &[u8], ciphertext_with_tag: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.check_nonce(nonce)?;
+        debug!("Decrypting {} bytes with {:?}", ciphertext_with_tag.len().saturating_sub(16), self);
+        match self {
+            Self::Aes256Gcm => aead_open::<Aes256Gcm>(key, nonce, ciphertext_with_tag, aad),
+            Self::Aes256GcmSiv => aead_open::<Aes256GcmSiv>(key, nonce, ciphertext_with_tag, aad),
+            Self::XChaCha20Poly1305 => aead_open::<XChaCha20Poly1305>(key, nonce, ciphertext_with_tag, aad),
+        }
         .map_err(|e| {
-            anyhow!("Decryption failed: {}", e)
-        })?;
-    info!("Decryption successful, length {}", plaintext.len());
-    Ok(plaintext)
-}
\ No newline at end of file
+            Error::AuthFailed(format!("Decryption failed: {}. Wrong password or the image/header was modified", e)).into()
+        })
+    }
+
+    fn check_nonce(self, nonce: &[u8]) -> Result<()> {
+        if nonce.len() != self.nonce_len() {
+            bail!("{:?} needs a {} byte nonce, got {}", self, self.nonce_len(), nonce.len());
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for CipherSuite {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "aes-256-gcm-siv" => Ok(Self::Aes256GcmSiv),
+            "xchacha20-poly1305" => Ok(Self::XChaCha20Poly1305),
+            _ => Err(format!("Unknown cipher: {} (expected aes-256-gcm, aes-256-gcm-siv or xchacha20-poly1305)", s)),
+        }
+    }
+}
+
+fn aead_seal<C: Aead + KeyInit>(key: &[u8; 32], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+    let cipher = C::new_from_slice(key).map_err(|_| aes_gcm::aead::Error)?;
+    cipher.encrypt(GenericArray::from_slice(nonce), Payload { msg: plaintext, aad })
+}
+
+fn aead_open<C: Aead + KeyInit>(key: &[u8; 32], nonce: &[u8], ciphertext_with_tag: &[u8], aad: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+    let cipher = C::new_from_slice(key).map_err(|_| aes_gcm::aead::Error)?;
+    cipher.decrypt(GenericArray::from_slice(nonce), Payload { msg: ciphertext_with_tag, aad })
+}
+
+/// Encrypt with AES-GCM, `aad` is authenticated but not encrypted
+pub(crate) fn encrypt(key: &[u8; 32], nonce_bytes: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, [u8; 16])> {
+    CipherSuite::Aes256Gcm.encrypt(key, nonce_bytes, plaintext, aad)
+}
+
+/// Decrypt mit AES-GCM, `aad` must match the one used for encryption
+pub(crate) fn decrypt(key: &[u8; 32], nonce_bytes: &[u8], ciphertext: &[u8], auth_tag: &[u8; 16], aad: &[u8]) -> Result<Vec<u8>> {
+    CipherSuite::Aes256Gcm.decrypt(key, nonce_bytes, ciphertext, auth_tag, aad)
+}
+
+/// Fill `buf` with random bytes from the OS
+pub(crate) fn fill_random(buf: &mut [u8]) {
+    OsRng.fill_bytes(buf);
+}
+
+/// Random data key, a new one for every encrypted image
+pub(crate) fn generate_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// What a key source gives to wrap or unwrap the data key
+pub enum KeyMaterial {
+    /// Stretched with Argon2, for password key slots
+    Password(String),
+    /// Used as it is, for key file slots
+    Key([u8; 32]),
+    /// X25519 public key of a recipient, can only wrap
+    Recipient([u8; 32]),
+    /// X25519 private key, unwraps the slots made for its public key
+    Identity([u8; 32]),
+}
+
+/// Where the key that wraps the data key comes from.
+/// Implement it to fetch keys from somewhere else, like a secret store.
+pub trait KeySource: Send + Sync {
+    /// Called once for every key slot sealed and every unlock
+    fn material(&self) -> error::Result<KeyMaterial>;
+}
+
+/// Password, stretched with Argon2
+pub struct Password(pub String);
+
+/// File whose SHA-256 hash is the key
+pub struct KeyFile(pub String);
+
+/// 32 byte key, used as it is. Wraps the same slots as a key file with this hash.
+pub struct RawKey(pub [u8; 32]);
+
+/// Password read from an environment variable
+pub struct PasswordEnv(pub String);
+
+/// Password printed by a shell command, without the trailing newline
+pub struct PasswordCommand(pub String);
+
+/// Public key file of a recipient, can only wrap
+pub struct Recipient(pub String);
+
+/// Private key file, unwraps the slots made for its public key
+pub struct Identity(pub String);
+
+impl KeySource for Password {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        Ok(KeyMaterial::Password(self.0.clone()))
+    }
+}
+
+impl KeySource for KeyFile {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        Ok(KeyMaterial::Key(file2key(&self.0)?.0))
+    }
+}
+
+impl KeySource for RawKey {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        Ok(KeyMaterial::Key(self.0))
+    }
+}
+
+impl KeySource for PasswordEnv {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        info!("Reading password from environment variable {}", self.0);
+        match env::var(&self.0) {
+            Ok(pw) => Ok(KeyMaterial::Password(pw)),
+            Err(env::VarError::NotPresent) => Err(Error::Invalid(format!("Environment variable {} is not set", self.0))),
+            Err(e) => Err(Error::Invalid(format!("Environment variable {}: {}", self.0, e))),
+        }
+    }
+}
+
+impl KeySource for PasswordCommand {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        info!("Running password command: {}", self.0);
+        #[cfg(windows)]
+        let mut command = process::Command::new("cmd");
+        #[cfg(windows)]
+        command.arg("/C");
+        #[cfg(not(windows))]
+        let mut command = process::Command::new("sh");
+        #[cfg(not(windows))]
+        command.arg("-c");
+        // The command may ask for a passphrase itself
+        let output = command.arg(&self.0)
+            .stdin(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit())
+            .output()
+            .map_err(|e| Error::Invalid(format!("Failed to run password command: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Invalid(format!("Password command failed: {}", output.status)));
+        }
+        let mut pw = String::from_utf8(output.stdout)
+            .map_err(|_| Error::Invalid("Password command printed invalid UTF-8".to_string()))?;
+        if pw.ends_with('\n') {
+            pw.pop();
+            if pw.ends_with('\r') {
+                pw.pop();
+            }
+        }
+        if pw.is_empty() {
+            return Err(Error::Invalid("Password command printed nothing".to_string()));
+        }
+        Ok(KeyMaterial::Password(pw))
+    }
+}
+
+impl KeySource for Recipient {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        Ok(KeyMaterial::Recipient(read_key_file(&self.0)?))
+    }
+}
+
+impl KeySource for Identity {
+    fn material(&self) -> error::Result<KeyMaterial> {
+        Ok(KeyMaterial::Identity(read_key_file(&self.0)?))
+    }
+}
+
+/// The `--password`, `--password-file`, `--password-env` and `--password-command` options
+#[derive(Debug, Clone, Default)]
+pub struct KeyOptions {
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+    pub password_command: Option<String>,
+}
+
+impl KeyOptions {
+    /// Key source of the option that is set, `None` if there is none
+    pub fn source(self) -> Option<Box<dyn KeySource>> {
+        if let Some(pw_file) = self.password_file {
+            Some(Box::new(KeyFile(pw_file)))
+        } else if let Some(pw) = self.password {
+            Some(Box::new(Password(pw)))
+        } else if let Some(name) = self.password_env {
+            Some(Box::new(PasswordEnv(name)))
+        } else {
+            self.password_command.map(|cmd| Box::new(PasswordCommand(cmd)) as Box<dyn KeySource>)
+        }
+    }
+}
+
+/// Wrap the data key with AES-GCM under `kek`
+fn wrap_key(kek: &[u8; 32], key: &[u8; 32]) -> Result<([u8; 12], [u8; 48])> {
+    let nonce = generate_nonce();
+    let (ciphertext, tag) = encrypt(kek, &nonce, key, &[])?;
+    let mut wrapped = [0u8; 48];
+    wrapped[..32].copy_from_slice(&ciphertext);
+    wrapped[32..].copy_from_slice(&tag);
+    Ok((nonce, wrapped))
+}
+
+/// Unwrap the data key, `None` if `kek` is the wrong key
+fn unwrap_key(kek: &[u8; 32], nonce: &[u8; 12], wrapped: &[u8; 48]) -> Option<[u8; 32]> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek));
+    let key = cipher.decrypt(Nonce::from_slice(nonce), &wrapped[..]).ok()?;
+    key.try_into().ok()
+}
+
+/// Wrap the data key into a new key slot
+pub(crate) fn seal_slot(source: &dyn KeySource, kdf: &KdfParams, key: &[u8; 32]) -> Result<KeySlot> {
+    match source.material()? {
+        KeyMaterial::Password(pw) => {
+            kdf.validate()?;
+            let (kek, salt) = pwd2key(&pw, None, kdf)?; // generate random salt
+            let (nonce, wrapped_key) = wrap_key(&kek, key)?;
+            Ok(KeySlot::Password { salt, kdf: *kdf, nonce, wrapped_key })
+        }
+        KeyMaterial::Key(kek) => {
+            let (nonce, wrapped_key) = wrap_key(&kek, key)?;
+            Ok(KeySlot::KeyFile { nonce, wrapped_key })
+        }
+        KeyMaterial::Recipient(public) => seal_x25519(&public, key),
+        KeyMaterial::Identity(secret) => {
+            let secret = StaticSecret::from(secret);
+            seal_x25519(PublicKey::from(&secret).as_bytes(), key)
+        }
+    }
+}
+
+/// Try to unwrap the data key from a key slot.
+/// `None` if the slot belongs to another kind of key or the key doesn't match
+pub(crate) fn open_slot(material: &KeyMaterial, slot: &KeySlot) -> Result<Option<[u8; 32]>> {
+    match (material, slot) {
+        (KeyMaterial::Password(pw), KeySlot::Password { salt, kdf, nonce, wrapped_key }) => {
+            kdf.check_limit()?;
+            let (kek, _salt) = pwd2key(pw, Some(*salt), kdf)?;
+            Ok(unwrap_key(&kek, nonce, wrapped_key))
+        }
+        (KeyMaterial::Key(kek), KeySlot::KeyFile { nonce, wrapped_key }) => {
+            Ok(unwrap_key(kek, nonce, wrapped_key))
+        }
+        (KeyMaterial::Identity(secret), KeySlot::X25519 { ephemeral, nonce, wrapped_key }) => {
+            let secret = StaticSecret::from(*secret);
+            let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral));
+            if !shared.was_contributory() {
+                return Ok(None);
+            }
+            let kek = x25519_kek(shared.as_bytes(), ephemeral, PublicKey::from(&secret).as_bytes())?;
+            Ok(unwrap_key(&kek, nonce, wrapped_key))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Find the slot that `source` unlocks, returns its index and the data key
+pub(crate) fn unlock(source: &dyn KeySource, slots: &[KeySlot]) -> Result<(usize, [u8; 32])> {
+    let material = source.material()?;
+    for (i, slot) in slots.iter().enumerate() {
+        if let Some(key) = open_slot(&material, slot)? {
+            info!("Unlocked key slot {}", i);
+            return Ok((i, key));
+        }
+        debug!("Key slot {} doesn't match", i);
+    }
+    Err(Error::WrongKey.into())
+}
+
+/// New X25519 key pair, returns (private, public)
+pub fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+    let secret = StaticSecret::from(generate_key());
+    let public = PublicKey::from(&secret);
+    (secret.to_bytes(), public.to_bytes())
+}
+
+/// Read a hex encoded 32 byte key, as written by `keygen`
+pub(crate) fn read_key_file(path: &str) -> Result<[u8; 32]> {
+    info!("Reading key from file: {}", path);
+    let text = fs::read_to_string(path)?;
+    let bytes = hex::decode(text.trim())
+        .map_err(|e| anyhow!("Invalid key file {}: {}", path, e))?;
+    bytes.try_into()
+        .map_err(|_| anyhow!("Invalid key file {}: expected 32 bytes", path))
+}
+
+/// Key encryption key for a recipient slot: HKDF-SHA256 over the ECDH shared secret,
+/// salted with both public keys
+fn x25519_kek(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut kek = [0u8; 32];
+    hk.expand(b"p2wviewer x25519 key slot", &mut kek)
+        .map_err(|e| anyhow!("HKDF failed: {}", e))?;
+    Ok(kek)
+}
+
+/// Wrap the data key for a recipient with an ephemeral ECDH key
+fn seal_x25519(recipient: &[u8; 32], key: &[u8; 32]) -> Result<KeySlot> {
+    let ephemeral_secret = StaticSecret::from(generate_key());
+    let ephemeral = PublicKey::from(&ephemeral_secret).to_bytes();
+    let shared = ephemeral_secret.diffie_hellman(&PublicKey::from(*recipient));
+    if !shared.was_contributory() {
+        bail!("Invalid recipient public key");
+    }
+    debug!("Wrapping key for recipient {}", hex::encode(recipient));
+    let kek = x25519_kek(shared.as_bytes(), &ephemeral, recipient)?;
+    let (nonce, wrapped_key) = wrap_key(&kek, key)?;
+    Ok(KeySlot::X25519 { ephemeral, nonce, wrapped_key })
+}
+
+/// New Ed25519 signing key pair, returns (private, public)
+pub fn generate_signing_keypair() -> ([u8; 32], [u8; 32]) {
+    let signing_key = SigningKey::from_bytes(&generate_key());
+    (signing_key.to_bytes(), signing_key.verifying_key().to_bytes())
+}
+
+/// What gets signed: the authenticated header data, the auth tag and the ciphertext.
+/// Key slots aren't covered, so rekeying keeps the signature valid.
+pub(crate) fn signature_message(aad: &[u8], auth_tag: &[u8; 16], ciphertext: &[u8]) -> Vec<u8> {
+    let mut digest = SignatureDigest::new(aad, auth_tag);
+    digest.update(ciphertext);
+    digest.message()
+}
+
+/// `signature_message` computed piece by piece, for ciphertext that is streamed
+pub(crate) struct SignatureDigest(Sha512);
+
+impl SignatureDigest {
+    pub fn new(aad: &[u8], auth_tag: &[u8; 16]) -> Self {
+        let mut hasher = Sha512::new();
+        hasher.update(aad);
+        hasher.update(auth_tag);
+        Self(hasher)
+    }
+
+    pub fn update(&mut self, ciphertext: &[u8]) {
+        self.0.update(ciphertext);
+    }
+
+    pub fn message(self) -> Vec<u8> {
+        let mut message = b"p2wviewer signature v1".to_vec();
+        message.extend_from_slice(&self.0.finalize());
+        message
+    }
+}
+
+impl io::Write for SignatureDigest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sign with the Ed25519 private key from `key_file`
+pub(crate) fn sign(key_file: &str, message: &[u8]) -> Result<Signature> {
+    let signing_key = SigningKey::from_bytes(&read_key_file(key_file)?);
+    let signature = signing_key.sign(message);
+    debug!("Signed by {}", hex::encode(signing_key.verifying_key().as_bytes()));
+    Ok(Signature {
+        signer: signing_key.verifying_key().to_bytes(),
+        signature: signature.to_bytes(),
+    })
+}
+
+/// Check a signature against the public key stored with it
+pub(crate) fn verify_signature(signature: &Signature, message: &[u8]) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(&signature.signer) else {
+        return false;
+    };
+    let sig = ed25519_dalek::Signature::from_bytes(&signature.signature);
+    key.verify(message, &sig).is_ok()
+}
@@ -1,158 +1,440 @@
+use crate::carrier::CarrierReader;
+use crate::compress::Decompressor;
 use crate::crypto::{
     pwd2key,
-    file2key,
     decrypt,
-    generate_token
+    generate_token,
+    unlock,
+    KeyMaterial,
+    KeySource,
+    SignatureDigest
+};
+use crate::error::{
+    self,
+    Error
+};
+use crate::header::{
+    Header,
+    Payload
+};
+use crate::stream::{
+    self,
+    Stream
+};
+use crate::parallel;
+use crate::verify::{
+    self,
+    SignatureStatus
 };
-use crate::header::Header;
 use crate::image_ops::{
-    load,
-    save,
-    img2byte,
-    byte2img
+    byte2img,
+    pixel_len,
+    PixelWriter
 };
 use anyhow::{
     Result,
+    anyhow,
     bail
 };
+use image::{
+    DynamicImage,
+    ImageFormat
+};
 use log::{
     info,
-    debug
+    debug,
+    warn
+};
+use std::fs::{
+    self,
+    File
+};
+use std::io::{
+    BufRead,
+    BufWriter,
+    Read,
+    Seek,
+    Write
+};
+use std::path::{
+    Path,
+    PathBuf
 };
-use regex::Regex;
-use std::fs;
-use std::path::PathBuf;
 
 pub struct DecryptOptions {
     pub input_path: PathBuf,
+    /// Output file, or a directory for an encrypted file to be restored under its original name
     pub output_path: PathBuf,
-    pub password: Option<String>,
-    pub password_file: Option<String>,
+    /// Password, key file, identity for images encrypted to a recipient, or any other key source
+    pub key: Option<Box<dyn KeySource>>,
+    /// Threads for decrypting, one per core if `None`
+    pub threads: Option<usize>,
 }
 
-fn image_bytes(path: &PathBuf) -> Result<Vec<u8>> {
-    if path.is_dir() {
-        info!("Input is a directory, searching for split parts...");
-        let re = Regex::new(r"\.(\d+)\.(png|PNG)$").unwrap();
-        let mut files_with_parts: Vec<(u32, PathBuf)> = Vec::new();
-
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let p = entry.path();
-            if p.is_file() {
-                if let Some(filename) = p.file_name().and_then(|f| f.to_str()) {
-                    if let Some(captures) = re.captures(filename) {
-                        if let Some(part_num_str) = captures.get(1) {
-                            if let Ok(part_num) = part_num_str.as_str().parse::<u32>() {
-                                files_with_parts.push((part_num, p.clone()));
-                            }
-                        }
-                    }
+/// Decrypt to the output path, returns the status of the signature
+pub fn run(opts: DecryptOptions) -> error::Result<SignatureStatus> {
+    Ok(parallel::install(opts.threads, || decrypt_to_file(&opts))?)
+}
+
+fn decrypt_to_file(opts: &DecryptOptions) -> Result<SignatureStatus> {
+    info!("Starting decryption for {:?}", opts.input_path);
+    let decryptor = Decryptor::open(&opts.input_path)?;
+    let header = decryptor.header().clone();
+    let key = key_source(opts)?;
+
+    // Decrypted straight from the carrier into the output file
+    let output_path = match header.payload {
+        Payload::Pixels => opts.output_path.clone(),
+        Payload::File { ref name, .. } => file_output_path(&opts.output_path, name)?,
+        Payload::Original { ref name, ref format, .. } => {
+            let path = file_output_path(&opts.output_path, name)?;
+            // The bytes are written as they were, whatever the extension says
+            if let Some(format) = ImageFormat::from_mime_type(format) {
+                if ImageFormat::from_path(&path).ok() != Some(format) {
+                    warn!("{:?} doesn't match the original {:?} format", path, format);
                 }
             }
+            path
         }
-
-        if files_with_parts.is_empty() {
-            bail!("No split image parts found in the directory: {:?}", path);
+    };
+    let result = match header.payload {
+        Payload::Pixels => {
+            let mut output = PixelWriter::create(&output_path, header.width, header.height, header.color)?;
+            decryptor.decrypt_to(key, &mut output).map_err(anyhow::Error::from)
+                .and_then(|signature| output.finish().map(|_| signature))
+        }
+        Payload::File { .. } | Payload::Original { .. } => {
+            let mut output = BufWriter::new(File::create(&output_path)?);
+            decryptor.decrypt_to(key, &mut output).map_err(anyhow::Error::from)
+                .and_then(|signature| Ok(output.flush().map(|_| signature)?))
+        }
+    };
+    let signature = match result {
+        Ok(signature) => signature,
+        Err(e) => {
+            // Don't leave the authenticated part of a broken image behind
+            let _ = fs::remove_file(&output_path);
+            return Err(e);
         }
+    };
+    info!("Decryption completed successfully: {:?}", output_path);
+    Ok(signature)
+}
 
-        // Sort by part number
-        files_with_parts.sort_by_key(|k| k.0);
+/// Decrypt noise images in memory, in any order, back into the image they hold,
+/// with the status of its signature. The images are consumed as they are read.
+pub fn decrypt_carriers(carriers: Vec<DynamicImage>, key: &dyn KeySource) -> error::Result<(DynamicImage, SignatureStatus)> {
+    Decryptor::from_images(carriers)?.decrypt_image(key)
+}
 
-        let first_path = files_with_parts[0].1.clone();
-        let first_img = load(&first_path)?;
-        let first_part_bytes = img2byte(&first_img);
-        if first_part_bytes.len() < crate::header::HEADER_SIZE {
-            bail!("First part image is too small to contain a header");
-        }
-        let header = Header::from_bytes(&first_part_bytes[..crate::header::HEADER_SIZE])?;
-        let total_len = header.payload_len as usize;
-        debug!("Expecting {} bytes based on header in part 1", total_len);
+/// Decrypt noise images in memory into `output`: the bytes of an encrypted file,
+/// or the samples of an image in its color type, wider samples little endian.
+/// The returned header tells which, the status whether it was signed.
+pub fn decrypt_to_writer<W: Write>(carriers: Vec<DynamicImage>, key: &dyn KeySource, output: W) -> error::Result<(Header, SignatureStatus)> {
+    let decryptor = Decryptor::from_images(carriers)?;
+    let header = decryptor.header().clone();
+    let signature = decryptor.decrypt_to(key, output)?;
+    Ok((header, signature))
+}
+
+/// Noise image(s) opened for decrypting. The header is read when they are
+/// opened, so it tells what they hold before anything is decrypted.
+pub struct Decryptor<'r> {
+    carrier: CarrierReader<'r>,
+}
 
-        let num_parts = files_with_parts.len();
-        let chunk_size = (total_len + num_parts - 1) / num_parts;
-        let last_chunk_size = total_len - (num_parts - 1) * chunk_size;
+impl<'r> Decryptor<'r> {
+    /// A noise image file, one of its split parts, or a directory with the parts
+    pub fn open(path: &Path) -> error::Result<Self> {
+        Ok(Self::new(CarrierReader::open(path)?))
+    }
+
+    /// Noise images in memory, in any order, consumed as they are read
+    pub fn from_images(images: Vec<DynamicImage>) -> error::Result<Self> {
+        Ok(Self::new(CarrierReader::from_images(images)?))
+    }
 
-        let mut f_bytes = first_part_bytes[0 .. std::cmp::min(chunk_size, first_part_bytes.len())].to_vec();
-        if f_bytes.len() != chunk_size {
-            bail!("First part doesn't contain enough data: {} < {}", f_bytes.len(), chunk_size);
+    /// Noise image files read from `readers`, from their position on, in any order.
+    /// PNGs are decoded row by row, the readers are rewound after looking at their part headers.
+    pub fn from_readers<R: BufRead + Seek + 'r>(readers: Vec<R>) -> error::Result<Self> {
+        Ok(Self::new(CarrierReader::from_readers(readers)?))
+    }
+
+    fn new(carrier: CarrierReader<'r>) -> Self {
+        debug!("Parsed header: {:?}", carrier.header());
+        Self { carrier }
+    }
+
+    pub fn header(&self) -> &Header {
+        self.carrier.header()
+    }
+
+    /// Decrypt into `output`: the bytes of an encrypted file, or the samples
+    /// of an image in its color type, wider samples little endian.
+    /// Returns whether the image was signed and by whom. A signature that doesn't
+    /// verify doesn't stop decrypting, the status tells it was forged or modified.
+    pub fn decrypt_to<W: Write>(mut self, key: &dyn KeySource, mut output: W) -> error::Result<SignatureStatus> {
+        let header = self.carrier.header().clone();
+        if header.segment_size.is_none() {
+            let (data, signature) = decrypt_whole(&header, &mut self.carrier, key)?;
+            output.write_all(&data)?;
+            return Ok(signature);
         }
-        debug!("Read {} bytes from part 1, total is now {}", f_bytes.len(), f_bytes.len());
-
-        for i in 1..num_parts {
-            let file_path = &files_with_parts[i].1;
-            let part_img = load(file_path)?;
-            let part_bytes = img2byte(&part_img);
-            let this_chunk_size = if i == num_parts - 1 { last_chunk_size } else { chunk_size };
-            if part_bytes.len() < this_chunk_size {
-                bail!("Part {} too small: {} < {}", i + 1, part_bytes.len(), this_chunk_size);
-            }
-            f_bytes.extend_from_slice(&part_bytes[0 .. this_chunk_size]);
-            debug!("Read {} bytes from part {}, total is now {}", this_chunk_size, i + 1, f_bytes.len());
+        let (stream, size) = open_stream(&header, key)?;
+        Ok(decrypt_stream(&stream, &header, &mut self.carrier, output, size)?)
+    }
+
+    /// Decrypt the image the noise image(s) hold, with the status of its signature
+    pub fn decrypt_image(self, key: &dyn KeySource) -> error::Result<(DynamicImage, SignatureStatus)> {
+        let header = self.header().clone();
+        if let Payload::File { ref name, .. } = header.payload {
+            return Err(Error::Invalid(format!("The carrier holds the file {:?}, not an image", name)));
         }
+        let mut data = Vec::new();
+        let signature = self.decrypt_to(key, &mut data)?;
+        let image = match header.payload {
+            Payload::Original { .. } => image::load_from_memory(&data)?,
+            _ => byte2img(&data, header.width, header.height, header.color)?,
+        };
+        Ok((image, signature))
+    }
+}
 
-        if f_bytes.len() != total_len {
-            bail!("Failed to reconstruct data. Expected {} bytes, got {}.", total_len, f_bytes.len());
+/// Payload without segments, decrypted in one piece, with the status of its signature
+fn decrypt_whole(header: &Header, carrier: &mut CarrierReader, key: &dyn KeySource) -> Result<(Vec<u8>, SignatureStatus)> {
+    // after header is the ciphertext alles
+    let mut ciphertext = Vec::new();
+    carrier.read_to_end(&mut ciphertext)?;
+    if header.version < 4 {
+        return Ok((legacy_decrypt(key, header, &ciphertext)?, SignatureStatus::Unsigned));
+    }
+    let signature = verify::check(header, &ciphertext)?;
+
+    // Unwrap the data key from the key slots
+    let (_slot, data_key) = unlock(key, &header.slots)?;
+    let data = header.cipher.decrypt(&data_key, &header.nonce, &ciphertext, &header.auth_tag, &header.aad()?)?;
+    Ok((data, signature))
+}
+
+/// Unwrap the data key of a segmented payload, with the number of bytes it decrypts to
+fn open_stream(header: &Header, key: &dyn KeySource) -> Result<(Stream, u64)> {
+    let (_slot, data_key) = unlock(key, &header.slots)?;
+    let stream = Stream::new(header, &data_key)?;
+    // All of these fields are in the AAD, so a mismatch means the header was modified.
+    // Fail the way the first segment would, compressed payloads are checked while decompressing
+    let modified = |e: &dyn std::fmt::Display| Error::AuthFailed(format!("{}. The header was modified", e));
+    let plaintext_len = stream::plaintext_len(header.payload_len, stream.segment_size())
+        .map_err(|e| modified(&e))?;
+    let size = match header.payload {
+        Payload::Pixels => pixel_len(header.width, header.height, header.color).map_err(|e| modified(&e))?,
+        Payload::File { size, .. } | Payload::Original { size, .. } => size,
+    };
+    if header.compression.is_none() && plaintext_len != size {
+        match header.payload {
+            Payload::Pixels => bail!(modified(&format_args!("Payload length doesn't match the {:?} image dimensions {}x{}", header.color, header.width, header.height))),
+            _ => bail!(modified(&format_args!("Payload length doesn't match the file size {}", size))),
         }
+    }
+    Ok((stream, size))
+}
 
-        Ok(f_bytes)
+/// Decrypt the stream into `output` and check the signature if there is one
+fn decrypt_stream<W: Write>(stream: &Stream, header: &Header, carrier: &mut CarrierReader, output: W, size: u64) -> Result<SignatureStatus> {
+    let mut digest = match header.signature {
+        Some(_) => Some(SignatureDigest::new(&header.aad()?, &header.auth_tag)),
+        None => None,
+    };
+    decrypt_payload(stream, header, carrier, output, size, digest.as_mut())?;
+    Ok(match digest {
+        Some(digest) => verify::check_digest(header, digest),
+        None => SignatureStatus::Unsigned,
+    })
+}
 
-    } else {
-        info!("Input is a single file.");
-        let mut img_bytes = img2byte(&load(path)?);
-        if img_bytes.len() < crate::header::HEADER_SIZE {
-            bail!("Input data too small to contain header");
+/// Decrypt the stream into `output`, decompressing it to `size` bytes if it was compressed
+fn decrypt_payload<W: Write>(
+    stream: &Stream,
+    header: &Header,
+    carrier: &mut CarrierReader,
+    output: W,
+    size: u64,
+    digest: Option<&mut SignatureDigest>,
+) -> Result<()> {
+    match header.compression {
+        Some(compression) => {
+            debug!("Decompressing {:?}", compression);
+            let mut decompressor = Decompressor::new(compression.codec, output, size)?;
+            stream.decrypt(header.payload_len, carrier, &mut decompressor, digest)?;
+            decompressor.finish()?;
         }
-        let header = Header::from_bytes(&img_bytes[..crate::header::HEADER_SIZE])?;
-        let expected_len = header.payload_len as usize;
-        if img_bytes.len() < expected_len {
-            bail!("Input image too small: {} < {}", img_bytes.len(), expected_len);
+        None => {
+            stream.decrypt(header.payload_len, carrier, output, digest)?;
         }
-        img_bytes.truncate(expected_len);
-        Ok(img_bytes)
     }
+    Ok(())
 }
 
-pub fn run(opts: DecryptOptions) -> Result<()> {
-    info!("Starting decryption for {:?}", opts.input_path);
-    let img_bytes = image_bytes(&opts.input_path)?;
+/// Where to restore an encrypted file: the output path, or the original
+/// file name inside it if the output is a directory
+fn file_output_path(output_path: &Path, name: &str) -> Result<PathBuf> {
+    info!("Encrypted data is the file {:?}", name);
+    if !output_path.is_dir() {
+        return Ok(output_path.to_path_buf());
+    }
+    // Only the last component, the name comes from the image
+    let name = Path::new(name).file_name()
+        .ok_or_else(|| anyhow!("Invalid file name {:?} in the header, give an output file", name))?;
+    Ok(output_path.join(name))
+}
 
-    let header = Header::from_bytes(&img_bytes[..crate::header::HEADER_SIZE])?;
-    debug!("Parsed header: {:?}", header);
-    
-    // after header is the ciphertext alles
-    let ciphertext = &img_bytes[crate::header::HEADER_SIZE..];
-    let auth_tag = &header.auth_tag;
+fn key_source(opts: &DecryptOptions) -> Result<&dyn KeySource> {
+    match opts.key {
+        Some(ref key) => Ok(key.as_ref()),
+        None => bail!(Error::Invalid("No password or password file provided".to_string())),
+    }
+}
 
+/// v1-v3: the password/keyfile key encrypts the payload directly, with a self-check token in front
+fn legacy_decrypt(source: &dyn KeySource, header: &Header, ciphertext: &[u8]) -> Result<Vec<u8>> {
     // Derive key
-    let key: [u8; 32] = if let Some(ref pw_file) = opts.password_file {
-        file2key(pw_file)?.0
-    } else if let Some(ref pw) = opts.password {
-        let (k, _salt) = pwd2key(pw, Some(header.salt))?;
-        k
-    } else {
-        bail!("No password or password file is provided");
+    let key: [u8; 32] = match source.material()? {
+        KeyMaterial::Key(k) => k,
+        KeyMaterial::Password(pw) => {
+            header.kdf.check_limit()?;
+            let (k, _salt) = pwd2key(&pw, Some(header.salt), &header.kdf)?;
+            k
+        }
+        _ => bail!(Error::Invalid("Images before version 4 need a password or password file".to_string())),
     };
 
-    let plaintext = decrypt(&key, &header.nonce, ciphertext, auth_tag)?;
+    // The key encrypts the payload itself, so it failing to authenticate is almost always
+    // a wrong password. There is no key slot to tell it apart from a modified image.
+    let mut plaintext = decrypt(&key, &header.nonce, ciphertext, &header.auth_tag, &header.aad()?)
+        .map_err(|e| {
+            warn!("{:#}", e);
+            Error::WrongKey
+        })?;
 
     // Verify token
     if plaintext.len() < 32 {
-        bail!("Decrypted data too small for token check");
+        bail!(Error::Truncated("Decrypted data too small for token check".to_string()));
     }
-    let token_stored = &plaintext[..32];
-    let img_data = &plaintext[32..];
     let token_check = generate_token(&key);
-    if token_stored != token_check {
-        bail!("Invalid password or corrupted data. Self-check failed.");
+    if plaintext[..32] != token_check {
+        bail!(Error::WrongKey);
     }
     info!("Self-check passed, key is valid");
-    // Schon im Header
-    let width = header.width;
-    let height = header.height;
-    let decrypted_img = byte2img(img_data, width, height)?;
-    save(&decrypted_img, &opts.output_path)?;
+    plaintext.drain(..32);
+    Ok(plaintext)
+}
 
-    info!("Decryption completed successfully: {:?}", opts.output_path);
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carrier::CarrierShape;
+    use crate::compress::{
+        Codec,
+        Compression
+    };
+    use crate::crypto::RawKey;
+    use crate::encrypt::{
+        encrypt_image,
+        encrypt_reader,
+        encrypt_to_pngs,
+        EncryptParams,
+        Input
+    };
+    use image::{
+        Rgb,
+        Rgba,
+        RgbaImage,
+        ImageBuffer
+    };
+    use std::io::Cursor;
+
+    const KEY: RawKey = RawKey([7; 32]);
+
+    fn split(parts: u32) -> EncryptParams {
+        EncryptParams { split: Some(parts), ..Default::default() }
+    }
+
+    #[test]
+    fn image_is_decrypted_from_shuffled_carriers() {
+        let image = DynamicImage::ImageRgb16(ImageBuffer::from_fn(40, 30, |x, y| Rgb([x as u16 * 1000, y as u16 * 2000, 65535])));
+        let mut carriers = encrypt_image(&image, &[&KEY], &split(3)).unwrap();
+        assert_eq!(carriers.len(), 3);
+        carriers.rotate_left(1);
+        assert_eq!(decrypt_carriers(carriers, &KEY).unwrap(), (image, SignatureStatus::Unsigned));
+    }
+
+    #[test]
+    fn file_is_streamed_from_reader_position() {
+        let contents: Vec<u8> = (0..50_000u32).map(|i| (i % 7) as u8).collect();
+        let mut bytes = b"skipped".to_vec();
+        bytes.extend_from_slice(&contents);
+        let mut reader = Cursor::new(bytes);
+        reader.set_position(7);
+        let params = EncryptParams { compress: Some(Compression::new(Codec::Deflate, 6).unwrap()), ..split(2) };
+        let carriers = encrypt_reader(reader, "data.bin", &[&KEY], &params).unwrap();
+
+        let mut output = Vec::new();
+        let (header, _) = decrypt_to_writer(carriers, &KEY, &mut output).unwrap();
+        assert_eq!(output, contents);
+        assert!(matches!(header.payload, Payload::File { ref name, size } if name == "data.bin" && size == contents.len() as u64));
+    }
+
+    #[test]
+    fn pngs_are_decrypted_from_readers_with_a_part_missing() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 48, |x, y| Rgba([x as u8, y as u8, 99, 200])));
+        let mut pngs = vec![Vec::new(); 3];
+        let mut outputs = pngs.iter_mut();
+        let params = EncryptParams { min_parts: Some(2), ..split(3) };
+        encrypt_to_pngs(Input::image(&image), &[&KEY], &params, |_, _| Ok(outputs.next().unwrap())).unwrap();
+
+        let decryptor = Decryptor::from_readers(pngs[1..].iter().map(|png| Cursor::new(png.as_slice())).collect()).unwrap();
+        assert_eq!((decryptor.header().width, decryptor.header().height), (64, 48));
+        assert_eq!(decryptor.decrypt_image(&KEY).unwrap().0, image);
+    }
+
+    #[test]
+    fn signature_status_is_returned() {
+        let (secret, public) = crate::crypto::generate_signing_keypair();
+        let key_file = std::env::temp_dir().join(format!("p2w-test-{}.key", std::process::id()));
+        fs::write(&key_file, hex::encode(secret)).unwrap();
+        let image = DynamicImage::new_rgb8(8, 8);
+        let params = EncryptParams { sign_key: Some(key_file.to_string_lossy().into_owned()), ..split(2) };
+        let carriers = encrypt_image(&image, &[&KEY], &params);
+        fs::remove_file(&key_file).unwrap();
+
+        let mut output = Vec::new();
+        let (_, signature) = decrypt_to_writer(carriers.unwrap(), &KEY, &mut output).unwrap();
+        assert_eq!(signature, SignatureStatus::Valid(public));
+    }
+
+    #[test]
+    fn wrong_key_is_reported() {
+        let carriers = encrypt_image(&DynamicImage::new_rgb8(8, 8), &[&KEY], &EncryptParams::default()).unwrap();
+        assert!(matches!(decrypt_carriers(carriers, &RawKey([8; 32])), Err(Error::WrongKey)));
+    }
+
+    #[test]
+    fn file_payload_is_not_an_image() {
+        let carriers = encrypt_reader(Cursor::new(b"not an image".to_vec()), "note.txt", &[&KEY], &EncryptParams::default()).unwrap();
+        assert!(matches!(decrypt_carriers(carriers, &KEY), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn bad_params_are_invalid() {
+        let image = DynamicImage::new_rgb8(8, 8);
+        let params = EncryptParams { min_parts: Some(4), ..split(3) };
+        assert!(matches!(encrypt_image(&image, &[&KEY], &params), Err(Error::Invalid(_))));
+        assert!(matches!(encrypt_image(&image, &[], &EncryptParams::default()), Err(Error::Invalid(_))));
+        let params = EncryptParams { carrier_shape: Some(CarrierShape::Width(u32::MAX)), ..Default::default() };
+        assert!(matches!(encrypt_image(&image, &[&KEY], &params), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn no_carriers_are_invalid() {
+        assert!(matches!(decrypt_carriers(Vec::new(), &KEY), Err(Error::Invalid(_))));
+    }
 }
@@ -1,118 +1,402 @@
+use crate::carrier::{
+    part_paths,
+    CarrierShape,
+    CarrierWriter,
+    Padding
+};
+use crate::compress::Compression;
 use crate::crypto::{
-    pwd2key,
-    file2key,
-    generate_nonce,
-    generate_token,
-    encrypt
+    generate_key,
+    seal_slot,
+    sign,
+    CipherSuite,
+    KdfParams,
+    KeySource,
+    Recipient,
+    SignatureDigest
+};
+use crate::error::{
+    self,
+    Error
+};
+use crate::header::{
+    Header,
+    Payload,
+    MAX_NAME_LEN
 };
-use crate::header::Header;
 use crate::image_ops::{
-    load,
-    save,
     img2byte,
-    byte2img
+    is_image,
+    PixelReader
+};
+use crate::parallel;
+use crate::stream::{
+    self,
+    Stream,
+    SEGMENT_SIZE
 };
 use anyhow::{
-    Result, 
+    Result,
     bail
 };
-use log::info;
+use image::{
+    ColorType,
+    DynamicImage,
+    ImageFormat,
+    ImageReader
+};
+use log::{
+    info,
+    warn
+};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{
+    self,
+    BufReader,
+    Cursor,
+    Read,
+    Seek,
+    SeekFrom,
+    Write
+};
 use std::path::{
     Path, 
     PathBuf
 };
+use std::rc::Rc;
 
 pub struct EncryptOptions {
     pub input_path: PathBuf,
     pub output_path: PathBuf,
-    pub password: Option<String>,
-    pub password_file: Option<String>,
+    /// Password, key file or any other key source
+    pub key: Option<Box<dyn KeySource>>,
+    /// Public key files, the image gets a key slot for each of them
+    pub recipients: Vec<String>,
+    /// Threads for encrypting, one per core if `None`
+    pub threads: Option<usize>,
+    /// Encrypt the input file as it is, even if it's an image.
+    /// Files that can't be read as images are always encrypted this way.
+    pub raw: bool,
+    /// Encrypt the image file byte for byte instead of its pixels and keep its
+    /// format, so decryption gives back the identical file with all metadata
+    pub lossless: bool,
+    pub params: EncryptParams,
+}
+
+/// How data is encrypted into the noise image(s), wherever it comes from and goes to
+#[derive(Debug, Clone, Default)]
+pub struct EncryptParams {
     pub split: Option<u32>,
+    /// Erasure code the split parts, any `min_parts` of them can be decrypted
+    pub min_parts: Option<u32>,
+    pub kdf: KdfParams,
+    /// Ed25519 private key file to sign the image with
+    pub sign_key: Option<String>,
+    pub cipher: CipherSuite,
+    /// Compress the data before encrypting it
+    pub compress: Option<Compression>,
+    /// Layout of the noise image(s), square by default so they don't reveal
+    /// the width of an encrypted image
+    pub carrier_shape: Option<CarrierShape>,
+    /// Round the carrier size up to hide the length of the data
+    pub pad: Option<Padding>,
 }
 
-pub fn run(opts: EncryptOptions) -> Result<()> {
+pub fn run(opts: EncryptOptions) -> error::Result<()> {
+    Ok(parallel::install(opts.threads, || encrypt_file(&opts))?)
+}
+
+fn encrypt_file(opts: &EncryptOptions) -> Result<()> {
     info!("Starting encryption for {:?}", opts.input_path);
+    // Files that aren't images are encrypted as they are
+    let mode = if opts.raw {
+        Mode::Raw
+    } else if !is_image(&opts.input_path) {
+        info!("Input is not an image, encrypting the raw file");
+        Mode::Raw
+    } else if opts.lossless {
+        Mode::Original
+    } else {
+        Mode::Pixels
+    };
+    let input = Input::open(&opts.input_path, mode)?;
 
-    // load image
-    let img = load(&opts.input_path)?;
-    let img_bytes = img2byte(&img);
-    let (original_width, original_height) = (img.width(), img.height());
+    // The data key is wrapped with the password/keyfile derived key and for every recipient
+    let recipients: Vec<Recipient> = opts.recipients.iter()
+        .map(|r| Recipient(r.clone()))
+        .collect();
+    let mut sources: Vec<&dyn KeySource> = recipients.iter()
+        .map(|r| r as &dyn KeySource)
+        .collect();
+    match opts.key {
+        Some(ref key) => sources.insert(0, key.as_ref()),
+        None if sources.is_empty() => bail!(Error::Invalid("No password or password file provided".to_string())),
+        None => {}
+    }
 
-    // Derive key
-    let (key, salt) = if let Some(ref pw_file) = opts.password_file {
-        file2key(pw_file)?
-    } else if let Some(ref pw) = opts.password {
-        pwd2key(pw, None)? // generate random salt
-    } else {
-        bail!("No password or password file provided");
+    // The output path, or `<stem>.<n>.<ext>` next to it for split parts
+    encrypt_to_pngs(input, &sources, &opts.params, |index, count| {
+        let path = part_paths(&opts.output_path, count).map_err(io::Error::other)?.swap_remove(index as usize);
+        info!("Saving image to {:?}", path);
+        File::create(path)
+    })?;
+    info!("Encryption completed successfully.");
+    Ok(())
+}
+
+/// Encrypt the pixels of `image` for `keys` into noise images in memory
+pub fn encrypt_image(image: &DynamicImage, keys: &[&dyn KeySource], params: &EncryptParams) -> error::Result<Vec<DynamicImage>> {
+    encrypt_to_images(Input::image(image), keys, params)
+}
+
+/// Encrypt the bytes of `reader` from its position to the end as a file called `name`
+/// into noise images in memory. The bytes are streamed, compressing or signing
+/// reads them twice, so the reader is rewound.
+pub fn encrypt_reader<R: Read + Seek>(reader: R, name: &str, keys: &[&dyn KeySource], params: &EncryptParams) -> error::Result<Vec<DynamicImage>> {
+    encrypt_to_images(Input::file(reader, name)?, keys, params)
+}
+
+/// Encrypt `input` for `keys` into noise images in memory, in part order
+pub fn encrypt_to_images(input: Input, keys: &[&dyn KeySource], params: &EncryptParams) -> error::Result<Vec<DynamicImage>> {
+    let carrier = encrypt(input, keys, params, |total_len, header_len, capacity, shape| {
+        CarrierWriter::in_memory(total_len, header_len, capacity, shape, params.split.unwrap_or(1), params.min_parts)
+    })?;
+    Ok(carrier.finish()?)
+}
+
+/// Encrypt `input` for `keys` into noise images encoded as PNGs row by row.
+/// Each part goes to the writer `outputs` makes from its index, counting from 0,
+/// and the number of parts, which is more than `params.split` if the data doesn't fit.
+pub fn encrypt_to_pngs<'w, W, F>(input: Input, keys: &[&dyn KeySource], params: &EncryptParams, outputs: F) -> error::Result<()>
+where
+    W: Write + 'w,
+    F: FnMut(u32, u32) -> io::Result<W> + 'w,
+{
+    let carrier = encrypt(input, keys, params, |total_len, header_len, capacity, shape| {
+        CarrierWriter::to_writers(total_len, header_len, capacity, shape, params.split.unwrap_or(1), params.min_parts, outputs)
+    })?;
+    carrier.finish()?;
+    Ok(())
+}
+
+/// Encrypt `input` with a new data key wrapped for each of `keys` into the
+/// carrier `create` makes for the total and header length, capacity and shape
+fn encrypt<'w, F>(input: Input, keys: &[&dyn KeySource], params: &EncryptParams, create: F) -> Result<CarrierWriter<'w>>
+where
+    F: FnOnce(u64, u64, u64, CarrierShape) -> Result<CarrierWriter<'w>>,
+{
+    let splits = params.split.unwrap_or(1);
+    if let Some(min_parts) = params.min_parts {
+        if min_parts == 0 || min_parts > splits {
+            bail!(Error::Invalid(format!("min_parts must be between 1 and the number of split parts ({}), got {}", splits, min_parts)));
+        }
+    }
+    if keys.is_empty() {
+        bail!(Error::Invalid("No password, key file or recipient to encrypt for".to_string()));
+    }
+
+    // Data to encrypt, read again for every pass. Compressing it gives the same bytes every time
+    let payload = || -> Result<Box<dyn Read + '_>> {
+        let reader = (input.reopen)()?;
+        match params.compress {
+            Some(compression) => compression.encoder(reader),
+            None => Ok(reader),
+        }
+    };
+    let (plaintext_len, unread) = match params.compress {
+        Some(compression) => {
+            // The header needs the compressed length before anything is encrypted
+            let len = io::copy(&mut compression.encoder(input.reader)?, &mut io::sink())?;
+            info!("Compressed {} bytes to {} with {:?}", input.len, len, compression);
+            (len, None)
+        }
+        None => (input.len, Some(input.reader)),
     };
 
-    let nonce = generate_nonce();
-    let token = generate_token(&key);
-
-    let mut payload = token.to_vec();
-    payload.extend_from_slice(&img_bytes);
-    let (ciphertext, auth_tag) = encrypt(&key, &nonce, &payload)?;
-
-    let payload_len = (crate::header::HEADER_SIZE + ciphertext.len()) as u64;
-    let header = Header::new(1, nonce, auth_tag, salt, original_width, original_height, payload_len);
-    let header_bytes = header.to_bytes();
-    
-    let mut f_bytes = Vec::with_capacity(payload_len as usize);
-    f_bytes.extend_from_slice(&header_bytes);
-    f_bytes.extend_from_slice(&ciphertext);
-
-    let splits = opts.split.unwrap_or(1);
-    if splits <= 1 {
-        // save as a single image
-        let pixels_needed = (f_bytes.len() as u32 + 3) / 4;
-        let new_width = original_width;
-        let new_height = (pixels_needed + new_width - 1) / new_width;
-        let new_image_size = (new_width * new_height * 4) as usize;
-
-        let mut padded_f_bytes = f_bytes;
-        padded_f_bytes.resize(new_image_size, 0);
-
-        info!("Creating single image of dimensions {}x{}", new_width, new_height);
-        let f_img = byte2img(&padded_f_bytes, new_width, new_height)?;
-        save(&f_img, &opts.output_path)?;
-    } else {
-        // split into multiple images
-        let chunk_size = (f_bytes.len() + splits as usize - 1) / splits as usize;
-        let output_dir = opts.output_path.parent().unwrap_or_else(|| Path::new("."));
-        let output_stem = opts.output_path.file_stem()
-            .ok_or_else(|| anyhow::anyhow!("Invaild output path"))?
+    // Random data key, wrapped into a key slot for every key source
+    let key = generate_key();
+    let slots = keys.iter()
+        .map(|source| seal_slot(*source, &params.kdf, &key))
+        .collect::<Result<Vec<_>>>()?;
+
+    let nonce = params.cipher.generate_nonce();
+
+    // The ciphertext length only depends on the plaintext length, so the header
+    // is complete before encrypting and can be passed as associated data.
+    // Every segment has its own tag, the one in the header stays zero.
+    let mut header = Header::new(
+        params.cipher,
+        nonce,
+        input.width,
+        input.height,
+        stream::ciphertext_len(plaintext_len, SEGMENT_SIZE)?,
+        slots,
+    );
+    header.segment_size = Some(SEGMENT_SIZE);
+    header.payload = input.payload;
+    header.color = input.color;
+    header.compression = params.compress;
+    let stream = Stream::new(&header, &key)?;
+
+    if let Some(ref sign_key) = params.sign_key {
+        // The signature is stored in the header, which comes before the ciphertext.
+        // Encrypting is deterministic, so a first pass only hashes the ciphertext.
+        let mut digest = SignatureDigest::new(&header.aad()?, &header.auth_tag);
+        stream.encrypt(plaintext_len, payload()?, &mut digest)?;
+        header.signature = Some(sign(sign_key, &digest.message())?);
+    }
+
+    let shape = match params.carrier_shape.unwrap_or(CarrierShape::Square) {
+        CarrierShape::Original if input.width == 0 => {
+            warn!("The input has no width to match, using a square carrier");
+            CarrierShape::Square
+        }
+        CarrierShape::Original => CarrierShape::Width(input.width),
+        shape => shape,
+    };
+    let total_len = header.total_len()?;
+    let capacity = match params.pad {
+        Some(padding) => padding.apply(total_len)?,
+        None => total_len,
+    };
+    let header_bytes = header.to_bytes()?;
+    let mut carrier = create(total_len, header_bytes.len() as u64, capacity, shape)?;
+    carrier.write_all(&header_bytes)?;
+    stream.encrypt(plaintext_len, unread.map_or_else(payload, Ok)?, &mut carrier)?;
+    Ok(carrier)
+}
+
+/// How a file is encrypted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Pixels of the image in its own color type
+    Pixels,
+    /// The image file byte for byte, with its format
+    Original,
+    /// Any file byte for byte
+    Raw,
+}
+
+/// Data to encrypt, with what the header records about it
+pub struct Input<'a> {
+    reader: Box<dyn Read + 'a>,
+    /// Reads the data again from the start, for every further pass
+    reopen: Box<dyn Fn() -> Result<Box<dyn Read + 'a>> + 'a>,
+    len: u64,
+    width: u32,
+    height: u32,
+    payload: Payload,
+    color: ColorType,
+}
+
+impl<'a> Input<'a> {
+    /// The file at `path` as `mode` says, the pixels of 8 bit PNGs are read row by row
+    pub fn open(path: &'a Path, mode: Mode) -> error::Result<Self> {
+        let reopen = Box::new(move || Ok(Input::open(path, mode)?.reader));
+        if mode == Mode::Pixels {
+            let pixels = PixelReader::open_native(path)?;
+            info!("Image loaded ({}x{} {:?})", pixels.width(), pixels.height(), pixels.color());
+            return Ok(Self {
+                len: pixels.byte_len(),
+                color: pixels.color(),
+                width: pixels.width(),
+                height: pixels.height(),
+                payload: Payload::Pixels,
+                reader: Box::new(pixels),
+                reopen,
+            });
+        }
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let name = path.file_name()
+            .ok_or_else(|| Error::Invalid("Invalid input path".to_string()))?
             .to_str()
-            .ok_or_else(|| anyhow::anyhow!("Invalid Unicode in output path"))?;
-        let output_ext = opts.output_path.extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("png");
-
-        for i in 0..splits {
-            let start = i as usize * chunk_size;
-            let end = std::cmp::min(start + chunk_size, f_bytes.len());
-            if start >= end { continue; }
-            let chunk = &f_bytes[start..end];
-
-            let width = original_width;
-            let pixels_needed = (chunk.len() as u32 + 3) / 4;
-            let new_height = (pixels_needed + width - 1) / width;
-            let new_image_size = (width * new_height * 4) as usize;
-
-            let mut padded_chunk = chunk.to_vec();
-            padded_chunk.resize(new_image_size, 0);
-
-            let split_img = byte2img(&padded_chunk, width, new_height)?;
-            let path = output_dir.join(format!(
-                "{}.{}.{}",
-                output_stem,
-                i + 1,
-                output_ext
-            ));
-            info!("Saving chunk {} to {:?}", i + 1, path);
-            save(&split_img, &path)?;
+            .ok_or_else(|| Error::Invalid("Invalid Unicode in input path".to_string()))?;
+        check_name(name)?;
+        info!("File loaded ({} bytes)", size);
+        if mode == Mode::Raw {
+            return Ok(Self {
+                reader: Box::new(BufReader::new(file)),
+                reopen,
+                len: size,
+                width: 0,
+                height: 0,
+                payload: Payload::File { name: name.to_string(), size },
+                color: ColorType::Rgba8,
+            });
         }
+
+        // Only the image header is decoded, for the format and the dimensions
+        let image = ImageReader::new(BufReader::new(&file)).with_guessed_format()?;
+        let format = image.format()
+            .or_else(|| ImageFormat::from_path(path).ok())
+            .ok_or_else(|| Error::Invalid("Unknown image format, use --raw to encrypt the file as it is".to_string()))?;
+        let (width, height) = image.into_dimensions()?;
+        info!("Keeping the original {:?} file ({}x{})", format, width, height);
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            reader: Box::new(BufReader::new(file)),
+            reopen,
+            len: size,
+            width,
+            height,
+            payload: Payload::Original { name: name.to_string(), size, format: format.to_mime_type().to_string() },
+            color: ColorType::Rgba8,
+        })
+    }
+
+    /// Samples of an image in memory
+    pub fn image(image: &DynamicImage) -> Self {
+        let data: Rc<[u8]> = img2byte(image).into();
+        let reopened = data.clone();
+        Self {
+            len: data.len() as u64,
+            reader: Box::new(Cursor::new(data)),
+            reopen: Box::new(move || Ok(Box::new(Cursor::new(reopened.clone())))),
+            width: image.width(),
+            height: image.height(),
+            payload: Payload::Pixels,
+            color: image.color(),
+        }
+    }
+
+    /// Bytes of a file called `name`, from the position of `reader` to its end.
+    /// Compressing or signing reads them twice, rewinding the reader.
+    pub fn file<R: Read + Seek + 'a>(mut reader: R, name: &str) -> error::Result<Self> {
+        check_name(name)?;
+        let start = reader.stream_position()?;
+        let size = reader.seek(SeekFrom::End(0))? - start;
+        reader.seek(SeekFrom::Start(start))?;
+        let reader = Rc::new(RefCell::new(reader));
+        let shared = reader.clone();
+        Ok(Self {
+            reader: Box::new(Shared(reader)),
+            reopen: Box::new(move || {
+                shared.borrow_mut().seek(SeekFrom::Start(start))?;
+                Ok(Box::new(Shared(shared.clone())))
+            }),
+            len: size,
+            width: 0,
+            height: 0,
+            payload: Payload::File { name: name.to_string(), size },
+            color: ColorType::Rgba8,
+        })
+    }
+}
+
+/// Reader shared by all passes over the data, only one of them reads at a time
+struct Shared<R>(Rc<RefCell<R>>);
+
+impl<R: Read> Read for Shared<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+fn check_name(name: &str) -> error::Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(Error::Invalid(format!("File name too long ({} bytes, at most {})", name.len(), MAX_NAME_LEN)));
     }
-    info!("Encryption completed successfully.");
     Ok(())
 }
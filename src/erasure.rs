@@ -0,0 +1,68 @@
+use crate::error::Error;
+use anyhow::{
+    Result,
+    bail
+};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Most bytes each part gets of a stripe
+pub const SHARD_LEN: u32 = 64 * 1024;
+
+/// Reed-Solomon code over split parts. The carrier bytes are cut into stripes
+/// of `data_parts` shards, each part stores one shard of every stripe and the
+/// parity parts store the parity of the stripe, so any `data_parts` of the
+/// parts give back the carrier.
+pub struct Erasure {
+    rs: ReedSolomon,
+    data_parts: usize,
+    parts: usize,
+    shard_len: usize,
+}
+
+impl Erasure {
+    pub fn new(data_parts: u32, parts: u32, shard_len: u32) -> Result<Self> {
+        if data_parts == 0 || data_parts >= parts {
+            bail!(Error::Invalid(format!("Erasure coding needs fewer required parts than parts ({} of {})", data_parts, parts)));
+        }
+        if parts > 256 {
+            bail!(Error::Invalid(format!("At most 256 erasure coded parts ({})", parts)));
+        }
+        Ok(Self {
+            rs: ReedSolomon::new(data_parts as usize, (parts - data_parts) as usize)?,
+            data_parts: data_parts as usize,
+            parts: parts as usize,
+            shard_len: shard_len as usize,
+        })
+    }
+
+    /// Carrier bytes in one stripe
+    pub fn stripe_len(&self) -> usize {
+        self.data_parts * self.shard_len
+    }
+
+    /// Shards of all parts for one stripe, shorter stripes are padded with zeros
+    pub fn encode(&self, stripe: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut shards: Vec<Vec<u8>> = (0..self.parts)
+            .map(|i| {
+                let start = (i * self.shard_len).min(stripe.len());
+                let end = ((i + 1) * self.shard_len).min(stripe.len());
+                let mut shard = if i < self.data_parts { stripe[start..end].to_vec() } else { Vec::new() };
+                shard.resize(self.shard_len, 0);
+                shard
+            })
+            .collect();
+        self.rs.encode(&mut shards)?;
+        Ok(shards)
+    }
+
+    /// Rebuild the stripe from `shards`, one per part. Parts that weren't
+    /// read are flagged `false`, at least `data_parts` must be there.
+    pub fn decode(&self, shards: &mut [(Vec<u8>, bool)], stripe: &mut Vec<u8>) -> Result<()> {
+        self.rs.reconstruct_data(shards)?;
+        stripe.clear();
+        for (shard, _) in &shards[..self.data_parts] {
+            stripe.extend_from_slice(shard);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io;
+
+/// What went wrong, returned by every public function of the crate.
+/// The crate-internal building blocks use `anyhow` and raise these where the
+/// cause is known, everything else ends up in `Other`.
+#[derive(Debug)]
+pub enum Error {
+    /// No key slot opens with the password, key file or identity,
+    /// or the payload of an image before version 4 doesn't decrypt with it
+    WrongKey,
+    /// The ciphertext or signature doesn't check out, the carrier was changed or damaged
+    AuthFailed(String),
+    /// Not a noise image, part or share
+    BadMagic(String),
+    /// A format version this build can't read, or that can't do what was asked
+    UnsupportedVersion(String),
+    /// Split parts or shares are missing
+    MissingPart(String),
+    /// The data ends before all bytes the header expects
+    Truncated(String),
+    Io(io::Error),
+    Image(image::ImageError),
+    /// Options or input that don't make sense
+    Invalid(String),
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongKey => write!(f, "Invalid password or key file. No key slot could be unlocked."),
+            Error::AuthFailed(msg)
+            | Error::BadMagic(msg)
+            | Error::UnsupportedVersion(msg)
+            | Error::MissingPart(msg)
+            | Error::Truncated(msg)
+            | Error::Invalid(msg) => write!(f, "{}", msg),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Image(e) => write!(f, "{}", e),
+            // With the causes
+            Error::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<io::Error>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<image::ImageError>() {
+            Ok(e) => e.into(),
+            Err(e) => Error::Other(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // Carrier reads pass their errors on wrapped in I/O errors
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            if let Some(Ok(inner)) = e.into_inner().map(|inner| inner.downcast::<Error>()) {
+                return *inner;
+            }
+            unreachable!("the inner error was checked");
+        }
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => e.into(),
+            e => Error::Image(e),
+        }
+    }
+}
@@ -1,7 +1,21 @@
+use crate::compress::{
+    Codec,
+    Compression
+};
+use crate::crypto::{
+    CipherSuite,
+    KdfAlgorithm,
+    KdfParams
+};
+use crate::error::{
+    self,
+    Error
+};
 use anyhow::{
     Result,
     bail
 };
+use image::ColorType;
 use byteorder::{
     ByteOrder,
     BigEndian
@@ -11,33 +25,379 @@ use log::{
     info,
     warn
 };
+use std::io::Read;
+
+// HEADER_SIZE_V1 is 65 bytes (57 + 8 for payload_len)
+pub const HEADER_SIZE_V1: usize = 65;
+// HEADER_SIZE_V2 is 82 bytes (65 + 17 for the KDF descriptor), also used by v3
+pub const HEADER_SIZE_V2: usize = 82;
+// Fixed part of a v4 header, followed by the records
+const CORE_SIZE_V4: usize = 53;
+// Sanity limit for the length field of a v4 header read from a stream
+const MAX_SIZE_V4: usize = 1 << 20;
 
-// HEADER_SIZE is now 65 bytes (57 + 8 for payload_len)
-pub const HEADER_SIZE: usize = 65;
+/// Version written by `encrypt`
+/// v1: original layout
+/// v2: adds the KDF descriptor
+/// v3: same layout as v2, the header is authenticated as AES-GCM associated data
+/// v4: random data key wrapped in key slots, variable length header made of records.
+///     With a stream record the ciphertext is split into separately authenticated segments
+pub const VERSION: u8 = 4;
 
 const MAGIC: &[u8; 4] = b"P2WV";
 
+// v4 record types
+const RECORD_KEY_SLOT: u8 = 1;
+const RECORD_SIGNATURE: u8 = 2;
+const RECORD_CIPHER: u8 = 3;
+const RECORD_STREAM: u8 = 4;
+const RECORD_PAYLOAD: u8 = 5;
+const RECORD_COLOR: u8 = 6;
+const RECORD_COMPRESSION: u8 = 7;
+
+// Payload kinds, pixels have no payload record
+const PAYLOAD_FILE: u8 = 1;
+const PAYLOAD_ORIGINAL: u8 = 2;
+
+// Color types of a pixels payload, RGBA8 has no color record
+const COLOR_TYPES: [(u8, ColorType); 10] = [
+    (1, ColorType::L8),
+    (2, ColorType::La8),
+    (3, ColorType::Rgb8),
+    (4, ColorType::Rgba8),
+    (5, ColorType::L16),
+    (6, ColorType::La16),
+    (7, ColorType::Rgb16),
+    (8, ColorType::Rgba16),
+    (9, ColorType::Rgb32F),
+    (10, ColorType::Rgba32F),
+];
+
+/// Longest file name stored in a payload record
+pub const MAX_NAME_LEN: usize = 1024;
+
+// Key slot kinds
+const SLOT_PASSWORD: u8 = 1;
+const SLOT_KEYFILE: u8 = 2;
+const SLOT_X25519: u8 = 3;
+
+/// Data key wrapped with AES-GCM under a password, keyfile or X25519 derived key.
+/// A header can have any number of them, each one unlocks the same data key
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySlot {
+    Password {
+        salt: [u8; 16],
+        kdf: KdfParams,
+        nonce: [u8; 12],
+        wrapped_key: [u8; 48], // key + auth tag
+    },
+    KeyFile {
+        nonce: [u8; 12],
+        wrapped_key: [u8; 48],
+    },
+    X25519 {
+        ephemeral: [u8; 32], // public key of the ephemeral ECDH key
+        nonce: [u8; 12],
+        wrapped_key: [u8; 48],
+    },
+}
+
+impl KeySlot {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            KeySlot::Password { salt, kdf, nonce, wrapped_key } => {
+                buf.push(SLOT_PASSWORD);
+                buf.extend_from_slice(salt);
+                buf.extend_from_slice(&kdf_to_bytes(kdf));
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(wrapped_key);
+            }
+            KeySlot::KeyFile { nonce, wrapped_key } => {
+                buf.push(SLOT_KEYFILE);
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(wrapped_key);
+            }
+            KeySlot::X25519 { ephemeral, nonce, wrapped_key } => {
+                buf.push(SLOT_X25519);
+                buf.extend_from_slice(ephemeral);
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(wrapped_key);
+            }
+        }
+        buf
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let Some((&kind, body)) = buf.split_first() else {
+            bail!(Error::Invalid("Empty key slot".to_string()));
+        };
+        match (kind, body.len()) {
+            (SLOT_PASSWORD, 93) => Ok(KeySlot::Password {
+                salt: body[..16].try_into()?,
+                kdf: kdf_from_bytes(&body[16..33])?,
+                nonce: body[33..45].try_into()?,
+                wrapped_key: body[45..93].try_into()?,
+            }),
+            (SLOT_KEYFILE, 60) => Ok(KeySlot::KeyFile {
+                nonce: body[..12].try_into()?,
+                wrapped_key: body[12..60].try_into()?,
+            }),
+            (SLOT_X25519, 92) => Ok(KeySlot::X25519 {
+                ephemeral: body[..32].try_into()?,
+                nonce: body[32..44].try_into()?,
+                wrapped_key: body[44..92].try_into()?,
+            }),
+            _ => bail!(Error::Invalid(format!("Invalid key slot (kind {}, {} bytes)", kind, body.len()))),
+        }
+    }
+}
+
+/// Ed25519 signature over the header and the ciphertext, see `crypto::signature_message`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub signer: [u8; 32], // public key
+    pub signature: [u8; 64],
+}
+
+/// What the encrypted data is
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    /// Samples of a width x height image in the color type of the header
+    Pixels,
+    /// Contents of an arbitrary file, restored byte for byte
+    File {
+        name: String, // file name without directories
+        size: u64,
+    },
+    /// Contents of an image file, restored byte for byte
+    Original {
+        name: String,
+        size: u64,
+        format: String, // MIME type
+    },
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Header {
     pub version: u8,
-    pub nonce: [u8; 12],
+    pub cipher: CipherSuite, // AES-256-GCM unless a cipher record says otherwise
+    pub nonce: Vec<u8>, // length depends on the cipher
     pub auth_tag: [u8; 16],
-    pub salt: [u8; 16],
+    pub salt: [u8; 16], // v1-v3 only, the password salt
     pub width: u32,
     pub height: u32,
-    pub payload_len: u64, // length of the f data
+    pub payload_len: u64, // v1-v3: header + ciphertext, v4: ciphertext only
+    pub kdf: KdfParams, // v1-v3 only, v1 files always used the Argon2 defaults
+    pub slots: Vec<KeySlot>, // v4 only
+    pub signature: Option<Signature>, // v4 only
+    pub segment_size: Option<u32>, // v4 only, plaintext bytes per segment of a streamed payload
+    pub payload: Payload, // always pixels before v4
+    pub color: ColorType, // v4 only, sample layout of a pixels payload, always RGBA8 before
+    pub compression: Option<Compression>, // v4 only, applied to the payload before encrypting
+}
+
+fn kdf_to_bytes(kdf: &KdfParams) -> [u8; 17] {
+    let mut buf = [0u8; 17];
+    buf[0] = kdf.algorithm.id();
+    BigEndian::write_u32(&mut buf[1..5], kdf.m_cost);
+    BigEndian::write_u32(&mut buf[5..9], kdf.t_cost);
+    BigEndian::write_u32(&mut buf[9..13], kdf.p_cost);
+    BigEndian::write_u32(&mut buf[13..17], kdf.output_len);
+    buf
+}
+
+fn kdf_from_bytes(buf: &[u8]) -> Result<KdfParams> {
+    let kdf = KdfParams {
+        algorithm: KdfAlgorithm::from_id(buf[0])?,
+        m_cost: BigEndian::read_u32(&buf[1..5]),
+        t_cost: BigEndian::read_u32(&buf[5..9]),
+        p_cost: BigEndian::read_u32(&buf[9..13]),
+        output_len: BigEndian::read_u32(&buf[13..17]),
+    };
+    // Not covered by the tag, check before anything is allocated for it
+    kdf.validate()?;
+    Ok(kdf)
+}
+
+fn payload_from_bytes(body: &[u8]) -> Result<Payload> {
+    let size = BigEndian::read_u64(&body[1..9]);
+    let text = |bytes: &[u8]| {
+        std::str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|_| Error::Invalid("Invalid text in payload record".to_string()))
+    };
+    match body[0] {
+        PAYLOAD_FILE => Ok(Payload::File { name: text(&body[9..])?, size }),
+        PAYLOAD_ORIGINAL => {
+            let format_len = *body.get(9).ok_or_else(|| Error::Truncated("Truncated payload record".to_string()))? as usize;
+            let format = body.get(10..10 + format_len)
+                .ok_or_else(|| Error::Truncated("Truncated payload record".to_string()))?;
+            Ok(Payload::Original { name: text(&body[10 + format_len..])?, size, format: text(format)? })
+        }
+        kind => bail!(Error::UnsupportedVersion(format!("Unknown payload kind {}", kind))),
+    }
+}
+
+fn push_record(buf: &mut Vec<u8>, kind: u8, body: &[u8]) -> Result<()> {
+    let len = u16::try_from(body.len())
+        .map_err(|_| Error::Invalid(format!("Header record of type {} too large ({} bytes)", kind, body.len())))?;
+    buf.push(kind);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(body);
+    Ok(())
 }
 
 impl Header {
-    /// New header
-    pub fn new(version: u8, nonce: [u8; 12], auth_tag: [u8; 16], salt: [u8; 16], width: u32, height: u32, payload_len: u64) -> Self {
-        debug!("New Header (version = {})", version);
-        Self { version, nonce, auth_tag, salt, width, height, payload_len }
+    /// New header with the current version
+    pub fn new(cipher: CipherSuite, nonce: Vec<u8>, width: u32, height: u32, payload_len: u64, slots: Vec<KeySlot>) -> Self {
+        debug!("New Header (version = {})", VERSION);
+        Self {
+            version: VERSION,
+            cipher,
+            nonce,
+            auth_tag: [0u8; 16],
+            salt: [0u8; 16],
+            width,
+            height,
+            payload_len,
+            kdf: KdfParams::default(),
+            slots,
+            signature: None,
+            segment_size: None,
+            payload: Payload::Pixels,
+            color: ColorType::Rgba8,
+            compression: None,
+        }
+    }
+
+    /// Serialized size of this header, fails if a record doesn't fit in it
+    pub fn size(&self) -> error::Result<usize> {
+        match self.version {
+            1 => Ok(HEADER_SIZE_V1),
+            2 | 3 => Ok(HEADER_SIZE_V2),
+            _ => Ok(CORE_SIZE_V4 + self.key_records()?.len() + self.data_records()?.len()),
+        }
     }
 
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
-        let mut buf = [0u8; HEADER_SIZE];
+    /// Length of the header plus the ciphertext following it
+    pub(crate) fn total_len(&self) -> Result<u64> {
+        match self.version {
+            1..=3 => Ok(self.payload_len),
+            _ => (self.size()? as u64).checked_add(self.payload_len)
+                .ok_or_else(|| anyhow::anyhow!("Invalid payload length {}", self.payload_len)),
+        }
+    }
+
+    /// Associated data for AES-GCM: the serialized header without the auth tag.
+    /// Empty before v3, so older files decrypt as they were written.
+    /// From v4 on the key slots are left out as well, they are authenticated by
+    /// their own wrapping and can change without touching the ciphertext.
+    /// So is the signature, which is computed over this data. The records
+    /// describing the data (cipher, stream, payload, color, compression)
+    /// are included.
+    pub fn aad(&self) -> error::Result<Vec<u8>> {
+        match self.version {
+            1 | 2 => Ok(Vec::new()),
+            3 => {
+                let mut buf = self.to_bytes()?;
+                buf.drain(17..33);
+                Ok(buf)
+            }
+            _ => {
+                let mut buf = Vec::with_capacity(CORE_SIZE_V4);
+                buf.extend_from_slice(MAGIC);
+                buf.push(self.version);
+                buf.extend_from_slice(&self.core_nonce());
+                buf.extend_from_slice(&self.width.to_be_bytes());
+                buf.extend_from_slice(&self.height.to_be_bytes());
+                buf.extend_from_slice(&self.payload_len.to_be_bytes());
+                buf.extend_from_slice(&self.data_records()?);
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Key slot and signature records, not covered by the associated data
+    fn key_records(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for slot in &self.slots {
+            push_record(&mut buf, RECORD_KEY_SLOT, &slot.to_bytes())?;
+        }
+        if let Some(ref sig) = self.signature {
+            let mut body = sig.signer.to_vec();
+            body.extend_from_slice(&sig.signature);
+            push_record(&mut buf, RECORD_SIGNATURE, &body)?;
+        }
+        Ok(buf)
+    }
+
+    /// Records describing the encrypted data, part of the associated data
+    fn data_records(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        if let Some(cipher) = self.cipher_record() {
+            push_record(&mut buf, RECORD_CIPHER, &cipher)?;
+        }
+        if let Some(segment_size) = self.segment_size {
+            push_record(&mut buf, RECORD_STREAM, &segment_size.to_be_bytes())?;
+        }
+        match self.payload {
+            Payload::Pixels => {}
+            Payload::File { ref name, size } => {
+                let mut body = vec![PAYLOAD_FILE];
+                body.extend_from_slice(&size.to_be_bytes());
+                body.extend_from_slice(name.as_bytes());
+                push_record(&mut buf, RECORD_PAYLOAD, &body)?;
+            }
+            Payload::Original { ref name, size, ref format } => {
+                let format_len = u8::try_from(format.len())
+                    .map_err(|_| Error::Invalid(format!("Image format {:?} too long ({} bytes)", format, format.len())))?;
+                let mut body = vec![PAYLOAD_ORIGINAL];
+                body.extend_from_slice(&size.to_be_bytes());
+                body.push(format_len);
+                body.extend_from_slice(format.as_bytes());
+                body.extend_from_slice(name.as_bytes());
+                push_record(&mut buf, RECORD_PAYLOAD, &body)?;
+            }
+        }
+        if let Some(compression) = self.compression {
+            let mut body = vec![compression.codec.id()];
+            body.extend_from_slice(&compression.level.to_be_bytes());
+            push_record(&mut buf, RECORD_COMPRESSION, &body)?;
+        }
+        if self.color != ColorType::Rgba8 {
+            let id = COLOR_TYPES.iter().find(|(_, c)| *c == self.color).map_or(0, |(id, _)| *id);
+            push_record(&mut buf, RECORD_COLOR, &[id])?;
+        }
+        Ok(buf)
+    }
+
+    /// AES-256-GCM keeps its nonce in the fixed part, so files written before
+    /// cipher suites existed stay the same. Other suites get a cipher record
+    /// with their id and nonce.
+    fn cipher_record(&self) -> Option<Vec<u8>> {
+        if self.cipher == CipherSuite::Aes256Gcm {
+            return None;
+        }
+        let mut body = vec![self.cipher.id()];
+        body.extend_from_slice(&self.nonce);
+        Some(body)
+    }
+
+    fn core_nonce(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        if self.cipher == CipherSuite::Aes256Gcm {
+            nonce.copy_from_slice(&self.nonce);
+        }
+        nonce
+    }
+
+    /// Serialize to bytes, fails if a record doesn't fit in the header
+    pub fn to_bytes(&self) -> error::Result<Vec<u8>> {
+        if self.version >= 4 {
+            return Ok(self.to_bytes_v4()?);
+        }
+        let mut buf = vec![0u8; self.size()?];
         buf[..4].copy_from_slice(MAGIC);
         buf[4] = self.version;
         buf[5..17].copy_from_slice(&self.nonce);
@@ -46,24 +406,85 @@ impl Header {
         BigEndian::write_u32(&mut buf[49..53], self.width);
         BigEndian::write_u32(&mut buf[53..57], self.height);
         BigEndian::write_u64(&mut buf[57..65], self.payload_len);
-        debug!("Serialized Header to {} bytes", HEADER_SIZE);
-        buf
+        if self.version >= 2 {
+            buf[65..82].copy_from_slice(&kdf_to_bytes(&self.kdf));
+        }
+        debug!("Serialized Header to {} bytes", buf.len());
+        Ok(buf)
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
-        if buf.len() < HEADER_SIZE {
-            bail!("Buf too small for Header (got {}, expected {})", buf.len(), HEADER_SIZE);
+    fn to_bytes_v4(&self) -> Result<Vec<u8>> {
+        let size = self.size()?;
+        // Readers refuse longer headers
+        if size > MAX_SIZE_V4 {
+            bail!(Error::Invalid(format!("Header too large ({} bytes, at most {})", size, MAX_SIZE_V4)));
         }
+        let mut buf = Vec::with_capacity(size);
+        buf.extend_from_slice(MAGIC);
+        buf.push(self.version);
+        buf.extend_from_slice(&(size as u32).to_be_bytes());
+        buf.extend_from_slice(&self.core_nonce());
+        buf.extend_from_slice(&self.auth_tag);
+        buf.extend_from_slice(&self.width.to_be_bytes());
+        buf.extend_from_slice(&self.height.to_be_bytes());
+        buf.extend_from_slice(&self.payload_len.to_be_bytes());
+        buf.extend_from_slice(&self.key_records()?);
+        buf.extend_from_slice(&self.data_records()?);
+        debug!("Serialized Header to {} bytes", buf.len());
+        Ok(buf)
+    }
 
+    /// Read a header from the start of `reader`, returns it with its serialized bytes
+    pub(crate) fn read<R: Read>(reader: &mut R) -> Result<(Self, Vec<u8>)> {
+        let mut buf = vec![0u8; 5];
+        reader.read_exact(&mut buf)?;
         if &buf[..4] != MAGIC {
             warn!("Invalid magic bytes");
-            bail!("Invalid header magic");
+            bail!(Error::BadMagic("Invalid header magic".to_string()));
+        }
+        let size = match buf[4] {
+            1 => HEADER_SIZE_V1,
+            2 | 3 => HEADER_SIZE_V2,
+            4 => {
+                buf.resize(9, 0);
+                reader.read_exact(&mut buf[5..])?;
+                let size = BigEndian::read_u32(&buf[5..9]) as usize;
+                if !(CORE_SIZE_V4..=MAX_SIZE_V4).contains(&size) {
+                    bail!(Error::Invalid(format!("Invalid header length {}", size)));
+                }
+                size
+            }
+            version => bail!(Error::UnsupportedVersion(format!("Unsupported header version {}", version))),
+        };
+        let start = buf.len();
+        buf.resize(size, 0);
+        reader.read_exact(&mut buf[start..])?;
+        Ok((Self::from_bytes(&buf)?, buf))
+    }
+
+    pub(crate) fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 5 {
+            bail!(Error::Truncated(format!("Buf too small for Header (got {}, expected {})", buf.len(), HEADER_SIZE_V1)));
+        }
+
+        if &buf[..4] != MAGIC {
+            warn!("Invalid magic bytes");
+            bail!(Error::BadMagic("Invalid header magic".to_string()));
         }
 
         let version = buf[4];
+        if version == 0 || version > VERSION {
+            bail!(Error::UnsupportedVersion(format!("Unsupported header version {}", version)));
+        }
+        if version >= 4 {
+            return Self::from_bytes_v4(buf);
+        }
+        let size = if version == 1 { HEADER_SIZE_V1 } else { HEADER_SIZE_V2 };
+        if buf.len() < size {
+            bail!(Error::Truncated(format!("Buf too small for Header (got {}, expected {})", buf.len(), size)));
+        }
 
-        let mut nonce = [0u8; 12];
-        nonce.copy_from_slice(&buf[5..17]);
+        let nonce = buf[5..17].to_vec();
 
         let mut auth_tag = [0u8; 16];
         auth_tag.copy_from_slice(&buf[17..33]);
@@ -75,8 +496,163 @@ impl Header {
         let height = BigEndian::read_u32(&buf[53..57]);
         let payload_len = BigEndian::read_u64(&buf[57..65]);
 
+        let kdf = if version >= 2 {
+            kdf_from_bytes(&buf[65..82])?
+        } else {
+            KdfParams::default()
+        };
+
         info!("Parsed Header (version = {}, payload_len = {})", version, payload_len);
 
-        Ok(Self { version, nonce, auth_tag, salt, width, height, payload_len })
+        Ok(Self {
+            version,
+            cipher: CipherSuite::Aes256Gcm,
+            nonce,
+            auth_tag,
+            salt,
+            width,
+            height,
+            payload_len,
+            kdf,
+            slots: Vec::new(),
+            signature: None,
+            segment_size: None,
+            payload: Payload::Pixels,
+            color: ColorType::Rgba8,
+            compression: None,
+        })
+    }
+
+    fn from_bytes_v4(buf: &[u8]) -> Result<Self> {
+        if buf.len() < CORE_SIZE_V4 {
+            bail!(Error::Truncated(format!("Buf too small for Header (got {}, expected {})", buf.len(), CORE_SIZE_V4)));
+        }
+        let size = BigEndian::read_u32(&buf[5..9]) as usize;
+        if size < CORE_SIZE_V4 {
+            bail!(Error::Invalid(format!("Invalid header length {}", size)));
+        }
+        if buf.len() < size {
+            bail!(Error::Truncated(format!("Buf too small for Header (got {}, expected {})", buf.len(), size)));
+        }
+
+        let mut cipher = CipherSuite::Aes256Gcm;
+        let mut nonce = buf[9..21].to_vec();
+
+        let mut auth_tag = [0u8; 16];
+        auth_tag.copy_from_slice(&buf[21..37]);
+
+        let width = BigEndian::read_u32(&buf[37..41]);
+        let height = BigEndian::read_u32(&buf[41..45]);
+        let payload_len = BigEndian::read_u64(&buf[45..53]);
+
+        let mut slots = Vec::new();
+        let mut signature = None;
+        let mut segment_size = None;
+        let mut payload = Payload::Pixels;
+        let mut color = None;
+        let mut compression = None;
+        let mut pos = CORE_SIZE_V4;
+        while pos < size {
+            if size - pos < 3 {
+                bail!(Error::Truncated(format!("Truncated header record at offset {}", pos)));
+            }
+            let kind = buf[pos];
+            let len = BigEndian::read_u16(&buf[pos + 1..pos + 3]) as usize;
+            let body = buf.get(pos + 3..pos + 3 + len)
+                .filter(|_| pos + 3 + len <= size)
+                .ok_or_else(|| Error::Truncated(format!("Truncated header record at offset {}", pos)))?;
+            match kind {
+                RECORD_KEY_SLOT => slots.push(KeySlot::from_bytes(body)?),
+                RECORD_SIGNATURE if body.len() == 96 && signature.is_none() => {
+                    signature = Some(Signature {
+                        signer: body[..32].try_into()?,
+                        signature: body[32..].try_into()?,
+                    });
+                }
+                RECORD_CIPHER if !body.is_empty() && cipher == CipherSuite::Aes256Gcm => {
+                    cipher = CipherSuite::from_id(body[0])?;
+                    if cipher == CipherSuite::Aes256Gcm || body.len() != 1 + cipher.nonce_len() {
+                        bail!(Error::Invalid("Invalid cipher record".to_string()));
+                    }
+                    nonce = body[1..].to_vec();
+                }
+                RECORD_STREAM if body.len() == 4 && segment_size.is_none() => {
+                    let size = BigEndian::read_u32(body);
+                    if size == 0 {
+                        bail!(Error::Invalid("Invalid stream record".to_string()));
+                    }
+                    segment_size = Some(size);
+                }
+                RECORD_PAYLOAD if body.len() >= 9 && payload == Payload::Pixels => {
+                    payload = payload_from_bytes(body)?;
+                }
+                RECORD_COLOR if body.len() == 1 && color.is_none() => {
+                    let (_, c) = COLOR_TYPES.iter().find(|(id, _)| *id == body[0])
+                        .ok_or_else(|| Error::UnsupportedVersion(format!("Unknown color type {}", body[0])))?;
+                    color = Some(*c);
+                }
+                RECORD_COMPRESSION if body.len() == 5 && compression.is_none() => {
+                    let level = BigEndian::read_i32(&body[1..5]);
+                    compression = Some(Compression::new(Codec::from_id(body[0])?, level)?);
+                }
+                _ => bail!(Error::Invalid(format!("Unknown or duplicate header record type {}", kind))),
+            }
+            pos += 3 + len;
+        }
+
+        if color.is_some() && payload != Payload::Pixels {
+            bail!(Error::Invalid("Color record for a file payload".to_string()));
+        }
+
+        info!("Parsed Header (version = {}, payload_len = {}, {} key slot(s))", buf[4], payload_len, slots.len());
+
+        Ok(Self {
+            version: buf[4],
+            cipher,
+            nonce,
+            auth_tag,
+            salt: [0u8; 16],
+            width,
+            height,
+            payload_len,
+            kdf: KdfParams::default(),
+            slots,
+            signature,
+            segment_size,
+            payload,
+            color: color.unwrap_or(ColorType::Rgba8),
+            compression,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(payload: Payload) -> Header {
+        let mut header = Header::new(CipherSuite::default(), vec![0u8; 12], 0, 0, 0, Vec::new());
+        header.payload = payload;
+        header
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn records_too_large_are_refused() {
+        let original = |format: String| header(Payload::Original { name: "a.png".to_string(), size: 1, format });
+        assert!(original("image/png".to_string()).to_bytes().is_ok());
+        assert!(matches!(original("x".repeat(256)).to_bytes(), Err(Error::Invalid(_))));
+
+        let file = header(Payload::File { name: "x".repeat(70_000), size: 1 });
+        assert!(matches!(file.to_bytes(), Err(Error::Invalid(_))));
+        assert!(matches!(file.aad(), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn header_is_read_back() {
+        let written = header(Payload::File { name: "notes.txt".to_string(), size: 42 });
+        let bytes = written.to_bytes().unwrap();
+        let (read, read_bytes) = Header::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read_bytes, bytes);
+        assert_eq!(read.payload, written.payload);
+    }
+}
@@ -1,18 +1,38 @@
+use crate::crypto::fill_random;
+use crate::error::Error;
+use crate::parallel;
 use anyhow::{
     Result,
     bail
 };
 use image::{
+    ColorType,
     DynamicImage,
-    ImageBuffer,
-    Rgba
+    GenericImageView,
+    ImageBuffer
 };
 use log::{
     debug,
     info,
     warn
 };
-use std::path::Path;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    BufWriter,
+    Read,
+    Seek,
+    SeekFrom,
+    Write
+};
+use std::path::{
+    Path,
+    PathBuf
+};
+use std::rc::Rc;
 
 pub fn load<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
     info!("Loading image from {:?}", path.as_ref());
@@ -24,36 +44,535 @@ pub fn load<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
     Ok(img)
 }
 
-pub fn save<P: AsRef<Path>>(img: &DynamicImage, path: P) -> Result<()> {
-    info!("Saving image to {:?}", path.as_ref());
-    img.save(&path).map_err(|e| {
-        warn!("Failed to save image: {}", e);
-        e
-    })?;
-    info!("Image saved");
-    Ok(())
+/// Whether the image crate recognizes the file as an image, by its content or extension
+pub fn is_image<P: AsRef<Path>>(path: P) -> bool {
+    image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .is_ok_and(|reader| reader.format().is_some())
+}
+
+/// Bytes of one sample of the color type
+fn sample_size(color: ColorType) -> usize {
+    (color.bytes_per_pixel() / color.channel_count()) as usize
+}
+
+/// Number of sample bytes of a width x height image
+pub fn pixel_len(width: u32, height: u32, color: ColorType) -> Result<u64> {
+    (width as u64 * height as u64).checked_mul(color.bytes_per_pixel() as u64)
+        .ok_or_else(|| Error::Invalid(format!("Image too large ({}x{} {:?})", width, height, color)).into())
 }
 
 // Byte to Image convert
-pub fn byte2img(data: &[u8], width: u32, height: u32) -> Result<DynamicImage> {
-    if data.len() != (width * height * 4) as usize {
-        bail!("Data length {} does not match dimensions {}x{} (expected {})",
+/// Rebuilds the image from its samples as returned by `img2byte`
+pub fn byte2img(data: &[u8], width: u32, height: u32, color: ColorType) -> Result<DynamicImage> {
+    let expected = pixel_len(width, height, color)?;
+    if data.len() as u64 != expected {
+        let msg = format!("Data length {} does not match dimensions {}x{} {:?} (expected {})",
             data.len(),
             width,
             height,
-            width * height * 4
+            color,
+            expected
         );
+        // Too few bytes for the dimensions means the data was cut off
+        bail!(if (data.len() as u64) < expected { Error::Truncated(msg) } else { Error::Invalid(msg) });
     }
-    debug!("Converting {} bytes to image {}x{}", data.len(), width, height);
-    let img: ImageBuffer<Rgba<u8>, _> = ImageBuffer::from_raw(width, height, data.to_vec())
-        .ok_or_else(|| anyhow::anyhow!("Failed to create image Buf from raw bytes"))?;
-    Ok(DynamicImage::ImageRgba8(img))
+    debug!("Converting {} bytes to {:?} image {}x{}", data.len(), color, width, height);
+    let u16s = || data.chunks_exact(2).map(|s| u16::from_le_bytes([s[0], s[1]])).collect::<Vec<_>>();
+    let f32s = || data.chunks_exact(4).map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]])).collect::<Vec<_>>();
+    let img = match color {
+        ColorType::L8 => ImageBuffer::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageLuma8),
+        ColorType::La8 => ImageBuffer::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageLumaA8),
+        ColorType::Rgb8 => ImageBuffer::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageRgb8),
+        ColorType::Rgba8 => ImageBuffer::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageRgba8),
+        ColorType::L16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLuma16),
+        ColorType::La16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLumaA16),
+        ColorType::Rgb16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgb16),
+        ColorType::Rgba16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgba16),
+        ColorType::Rgb32F => ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgb32F),
+        ColorType::Rgba32F => ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgba32F),
+        _ => bail!(Error::Invalid(format!("Unsupported color type {:?}", color))),
+    };
+    img.ok_or_else(|| anyhow::anyhow!("Failed to create image Buf from raw bytes"))
 }
 
 /// image to byte
+/// The samples in the image's own color type, wider samples little endian
 pub fn img2byte(img: &DynamicImage) -> Vec<u8> {
-    let rgba = img.to_rgba8();
-    let buf = rgba.into_raw();
-    debug!("Converted image {}x{} to {} bytes", img.width(), img.height(), buf.len());
+    let mut buf = img.as_bytes().to_vec();
+    let sample = sample_size(img.color());
+    if cfg!(target_endian = "big") && sample > 1 {
+        for s in buf.chunks_exact_mut(sample) {
+            s.reverse();
+        }
+    }
+    debug!("Converted {:?} image {}x{} to {} bytes", img.color(), img.width(), img.height(), buf.len());
     buf
-}
\ No newline at end of file
+}
+
+/// RGBA8 bytes of an image
+fn img2rgba(img: &DynamicImage) -> Result<Vec<u8>> {
+    // 8 bit images are converted row by row, in parallel with the `parallel` feature
+    let samples = match img {
+        DynamicImage::ImageRgba8(buf) => Some((png::ColorType::Rgba, buf.as_raw())),
+        DynamicImage::ImageRgb8(buf) => Some((png::ColorType::Rgb, buf.as_raw())),
+        DynamicImage::ImageLumaA8(buf) => Some((png::ColorType::GrayscaleAlpha, buf.as_raw())),
+        DynamicImage::ImageLuma8(buf) => Some((png::ColorType::Grayscale, buf.as_raw())),
+        _ => None,
+    };
+    match samples {
+        Some((color, data)) => {
+            let width = img.width() as usize;
+            let len = usize::try_from(pixel_len(img.width(), img.height(), ColorType::Rgba8)?)?;
+            let mut buf = vec![0u8; len];
+            parallel::rows(data, width * color.samples(), &mut buf, width * 4, |src, dst| row_to_rgba(color, src, dst));
+            Ok(buf)
+        }
+        None => Ok(img.to_rgba8().into_raw()),
+    }
+}
+
+/// Save the image in its own color type if the format can store it,
+/// otherwise converted to the closest one that works
+fn save_converted(img: &DynamicImage, path: &Path) -> Result<()> {
+    let err = match img.save(path) {
+        Err(image::ImageError::Unsupported(e)) => e,
+        result => return Ok(result?),
+    };
+    let wide = sample_size(img.color()) > 1;
+    let fallbacks = [
+        (wide).then(|| DynamicImage::ImageRgba16(img.to_rgba16())),
+        Some(DynamicImage::ImageRgba8(img.to_rgba8())),
+        Some(DynamicImage::ImageRgb8(img.to_rgb8())),
+    ];
+    for fallback in fallbacks.into_iter().flatten() {
+        if fallback.color() != img.color() && fallback.save(path).is_ok() {
+            warn!("{:?} can't store {:?} ({}), saved as {:?}", path, img.color(), err, fallback.color());
+            return Ok(());
+        }
+    }
+    bail!("Failed to save {:?}: {}", path, err)
+}
+
+/// PNG color type and bit depth storing the samples of the color type unchanged
+fn png_color(color: ColorType) -> Option<(png::ColorType, png::BitDepth)> {
+    let png_color = match color {
+        ColorType::L8 | ColorType::L16 => png::ColorType::Grayscale,
+        ColorType::La8 | ColorType::La16 => png::ColorType::GrayscaleAlpha,
+        ColorType::Rgb8 | ColorType::Rgb16 => png::ColorType::Rgb,
+        ColorType::Rgba8 | ColorType::Rgba16 => png::ColorType::Rgba,
+        _ => return None,
+    };
+    let depth = if sample_size(color) == 2 { png::BitDepth::Sixteen } else { png::BitDepth::Eight };
+    Some((png_color, depth))
+}
+
+/// Color type of the samples in a PNG row, if it has one
+fn image_color(color: png::ColorType, depth: png::BitDepth) -> Option<ColorType> {
+    [
+        ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8,
+        ColorType::L16, ColorType::La16, ColorType::Rgb16, ColorType::Rgba16,
+    ].into_iter().find(|&c| png_color(c) == Some((color, depth)))
+}
+
+/// Swap the bytes of every 16 bit sample, PNG stores them big endian
+fn swap_samples(data: &mut [u8]) {
+    for s in data.chunks_exact_mut(2) {
+        s.swap(0, 1);
+    }
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("png"))
+}
+
+/// First `len` RGBA8 bytes of an image, without converting all of it
+pub fn rgba_prefix(img: &DynamicImage, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = img.pixels()
+        .take(len.div_ceil(4))
+        .flat_map(|(_, _, px)| px.0)
+        .collect();
+    bytes.truncate(len);
+    bytes
+}
+
+/// Whether the file starts with the PNG signature, whatever its name
+pub fn is_png_file(path: &Path) -> bool {
+    File::open(path).is_ok_and(|mut f| has_png_signature(&mut f))
+}
+
+/// Whether the bytes read from `source` start with the PNG signature
+fn has_png_signature<R: Read>(source: &mut R) -> bool {
+    let mut signature = [0u8; 8];
+    source.read_exact(&mut signature).is_ok_and(|_| signature == *b"\x89PNG\r\n\x1a\n")
+}
+
+/// An image file being read, which can be rewound
+pub trait ImageSource: BufRead + Seek {}
+
+impl<T: BufRead + Seek> ImageSource for T {}
+
+/// Pixel bytes of an image, read without holding the whole image.
+/// Non-interlaced PNGs are decoded row by row, everything else is decoded
+/// completely by the image crate.
+pub struct PixelReader<'r> {
+    width: u32,
+    height: u32,
+    color: ColorType,
+    len: u64,
+    source: PixelSource<'r>,
+}
+
+enum PixelSource<'r> {
+    Png {
+        reader: Box<png::Reader<Box<dyn ImageSource + 'r>>>,
+        color: png::ColorType,
+        rgba: bool, // rows converted to RGBA8, otherwise kept as they are
+        row: Vec<u8>, // current row
+        pos: usize,
+    },
+    Decoded {
+        data: Vec<u8>,
+        pos: usize,
+    },
+}
+
+impl<'r> PixelReader<'r> {
+    /// RGBA8 bytes of the image, whatever its color type
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_as(path.as_ref(), true)
+    }
+
+    /// Samples of the image in its own color type, as returned by `img2byte`
+    pub fn open_native<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_as(path.as_ref(), false)
+    }
+
+    fn open_as(path: &Path, rgba: bool) -> Result<Self> {
+        if is_png_file(path) {
+            let mut source = BufReader::new(File::open(path)?);
+            if let Some(color) = streamed_color(&mut source, rgba)? {
+                debug!("Streaming the rows of {:?}", path);
+                return Self::stream_png(source, color, rgba);
+            }
+        }
+        Self::decoded(&load(path)?, rgba)
+    }
+
+    /// RGBA8 bytes of the image file read from `source`, which starts at its position
+    pub fn from_reader<R: ImageSource + 'r>(mut source: R) -> Result<Self> {
+        let start = source.stream_position()?;
+        let png = has_png_signature(&mut source);
+        source.seek(SeekFrom::Start(start))?;
+        if png {
+            if let Some(color) = streamed_color(&mut source, true)? {
+                return Self::stream_png(source, color, true);
+            }
+        }
+        let img = image::ImageReader::new(source).with_guessed_format()?.decode()?;
+        Self::decoded(&img, true)
+    }
+
+    /// Rows of the PNG read from `source` as `color`, which `streamed_color` gave
+    fn stream_png<R: ImageSource + 'r>(source: R, color: ColorType, rgba: bool) -> Result<Self> {
+        let mut decoder = png::Decoder::new(Box::new(source) as Box<dyn ImageSource + 'r>);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let reader = decoder.read_info()?;
+        let info = reader.info();
+        let (width, height) = (info.width, info.height);
+        debug!("Streaming {:?} rows ({}x{})", info.color_type, width, height);
+        let png_color = info.color_type;
+        Ok(Self {
+            width,
+            height,
+            color,
+            len: pixel_len(width, height, color)?,
+            source: PixelSource::Png { reader: Box::new(reader), color: png_color, rgba, row: Vec::new(), pos: 0 },
+        })
+    }
+
+    /// Bytes of an image decoded completely
+    fn decoded(img: &DynamicImage, rgba: bool) -> Result<Self> {
+        let (color, data) = if rgba { (ColorType::Rgba8, img2rgba(img)?) } else { (img.color(), img2byte(img)) };
+        Ok(Self {
+            width: img.width(),
+            height: img.height(),
+            color,
+            len: data.len() as u64,
+            source: PixelSource::Decoded { data, pos: 0 },
+        })
+    }
+
+    /// RGBA8 bytes of an image in memory
+    pub fn from_image(img: DynamicImage) -> Self {
+        let (width, height) = (img.width(), img.height());
+        let data = img.into_rgba8().into_raw();
+        Self {
+            width,
+            height,
+            color: ColorType::Rgba8,
+            len: data.len() as u64,
+            source: PixelSource::Decoded { data, pos: 0 },
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Color type of the bytes read
+    pub fn color(&self) -> ColorType {
+        self.color
+    }
+
+    /// Number of pixel bytes
+    pub fn byte_len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Color type the rows of the PNG in `source` are read as, `None` if the image
+/// crate has to decode it. `source` is rewound to where the PNG starts.
+fn streamed_color<R: ImageSource>(source: &mut R, rgba: bool) -> Result<Option<ColorType>> {
+    let start = source.stream_position()?;
+    let color = {
+        let mut decoder = png::Decoder::new(&mut *source);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let reader = decoder.read_info()?;
+        let info = reader.info();
+        // Transparency chunks and low bit depths are left to the image crate
+        let native = image_color(info.color_type, info.bit_depth)
+            .filter(|_| !info.interlaced && info.trns.is_none());
+        match native {
+            Some(color) if rgba && sample_size(color) == 1 => Some(ColorType::Rgba8),
+            Some(color) if !rgba => Some(color),
+            _ => None,
+        }
+    };
+    source.seek(SeekFrom::Start(start))?;
+    Ok(color)
+}
+
+/// Expand one 8 bit row to RGBA8, the same way `DynamicImage::to_rgba8` does
+fn row_to_rgba(color: png::ColorType, data: &[u8], row: &mut [u8]) {
+    match color {
+        png::ColorType::Rgba => row.copy_from_slice(data),
+        png::ColorType::Rgb => {
+            for (px, out) in data.chunks_exact(3).zip(row.chunks_exact_mut(4)) {
+                out.copy_from_slice(&[px[0], px[1], px[2], 255]);
+            }
+        }
+        png::ColorType::GrayscaleAlpha => {
+            for (px, out) in data.chunks_exact(2).zip(row.chunks_exact_mut(4)) {
+                out.copy_from_slice(&[px[0], px[0], px[0], px[1]]);
+            }
+        }
+        png::ColorType::Grayscale => {
+            for (&g, out) in data.iter().zip(row.chunks_exact_mut(4)) {
+                out.copy_from_slice(&[g, g, g, 255]);
+            }
+        }
+        png::ColorType::Indexed => unreachable!("indexed PNGs are decoded by the image crate"),
+    }
+}
+
+impl Read for PixelReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.source {
+            PixelSource::Png { ref mut reader, color, rgba, ref mut row, ref mut pos } => {
+                if *pos == row.len() {
+                    match reader.next_row()? {
+                        Some(next) if rgba => {
+                            row.resize(next.data().len() / color.samples() * 4, 0);
+                            row_to_rgba(color, next.data(), row);
+                        }
+                        Some(next) => {
+                            row.clear();
+                            row.extend_from_slice(next.data());
+                            if self.color.bytes_per_pixel() > self.color.channel_count() {
+                                swap_samples(row);
+                            }
+                        }
+                        None => return Ok(0),
+                    }
+                    *pos = 0;
+                }
+                let n = buf.len().min(row.len() - *pos);
+                buf[..n].copy_from_slice(&row[*pos..*pos + n]);
+                *pos += n;
+                Ok(n)
+            }
+            PixelSource::Decoded { ref data, ref mut pos } => {
+                let n = (&data[*pos..]).read(buf)?;
+                *pos += n;
+                Ok(n)
+            }
+        }
+    }
+}
+
+/// Writes pixel bytes to an image file, to a PNG written to any `Write`, or to
+/// an image in memory. PNGs that can store the color type are encoded row by
+/// row, everything else is collected and saved by the image crate in `finish`.
+pub struct PixelWriter<'w> {
+    path: Option<PathBuf>, // `None` for an image kept in memory or written to a `Write`
+    width: u32,
+    height: u32,
+    color: ColorType,
+    len: u64,
+    written: u64,
+    sink: PixelSink<'w>,
+}
+
+enum PixelSink<'w> {
+    Png {
+        writer: Box<png::StreamWriter<'static, Encoded>>,
+        encoded: Encoded,
+        output: Box<dyn Write + 'w>,
+        wide: bool, // 16 bit samples, swapped to big endian
+        odd: Option<u8>, // first byte of a sample split between writes
+    },
+    Buffered(Vec<u8>),
+}
+
+/// PNG bytes the encoder wrote that weren't passed on to the output yet.
+/// The encoder only takes writers that live forever and writes the last chunk
+/// when it is dropped, so it writes here and its output errors aren't lost.
+#[derive(Clone, Default)]
+struct Encoded(Rc<RefCell<Vec<u8>>>);
+
+impl Encoded {
+    fn pass_on(&self, output: &mut dyn Write) -> io::Result<()> {
+        let mut bytes = self.0.borrow_mut();
+        output.write_all(&bytes)?;
+        bytes.clear();
+        Ok(())
+    }
+}
+
+impl Write for Encoded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'w> PixelWriter<'w> {
+    pub fn create<P: AsRef<Path>>(path: P, width: u32, height: u32, color: ColorType) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let len = pixel_len(width, height, color)?;
+        info!("Saving image to {:?}", path);
+        let sink = match png_color(color).filter(|_| is_png(&path)) {
+            Some(_) => png_sink(Box::new(BufWriter::new(File::create(&path)?)), width, height, color)?,
+            None => PixelSink::Buffered(Vec::new()),
+        };
+        Ok(Self { path: Some(path), width, height, color, len, written: 0, sink })
+    }
+
+    /// PNG encoded row by row into `output`, the color type needs to be one PNG can store
+    pub fn png<W: Write + 'w>(output: W, width: u32, height: u32, color: ColorType) -> Result<Self> {
+        let len = pixel_len(width, height, color)?;
+        let sink = png_sink(Box::new(output), width, height, color)?;
+        Ok(Self { path: None, width, height, color, len, written: 0, sink })
+    }
+
+    /// Image built in memory, returned by `finish`
+    pub fn memory(width: u32, height: u32, color: ColorType) -> Result<Self> {
+        let len = pixel_len(width, height, color)?;
+        let sink = PixelSink::Buffered(Vec::with_capacity(usize::try_from(len)?));
+        Ok(Self { path: None, width, height, color, len, written: 0, sink })
+    }
+
+    /// Number of pixel bytes the image needs
+    pub fn byte_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Fill the rest of the image with random bytes, so the padding looks
+    /// like the ciphertext, and write it out. Images in memory are returned.
+    pub fn finish(mut self) -> Result<Option<DynamicImage>> {
+        let mut noise = [0u8; 4096];
+        while self.written < self.byte_len() {
+            let n = (self.byte_len() - self.written).min(noise.len() as u64) as usize;
+            fill_random(&mut noise[..n]);
+            self.write_all(&noise[..n])?;
+        }
+        match self.sink {
+            PixelSink::Png { writer, encoded, mut output, .. } => {
+                writer.finish()?;
+                encoded.pass_on(&mut output)?;
+                output.flush()?;
+            }
+            PixelSink::Buffered(data) => {
+                let img = byte2img(&data, self.width, self.height, self.color)?;
+                match self.path {
+                    Some(path) => save_converted(&img, &path)?,
+                    None => return Ok(Some(img)),
+                }
+            }
+        }
+        info!("Image saved");
+        Ok(None)
+    }
+}
+
+/// Stream writer for a PNG of the color type
+fn png_sink<'w>(output: Box<dyn Write + 'w>, width: u32, height: u32, color: ColorType) -> Result<PixelSink<'w>> {
+    let Some((png_color, depth)) = png_color(color) else {
+        bail!(Error::Invalid(format!("PNG can't store {:?} samples", color)));
+    };
+    let encoded = Encoded::default();
+    let mut encoder = png::Encoder::new(encoded.clone(), width, height);
+    encoder.set_color(png_color);
+    encoder.set_depth(depth);
+    Ok(PixelSink::Png {
+        writer: Box::new(encoder.write_header()?.into_stream_writer()?),
+        encoded,
+        output,
+        wide: depth == png::BitDepth::Sixteen,
+        odd: None,
+    })
+}
+
+impl Write for PixelWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.byte_len() - self.written) as usize;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "More pixel bytes than the image holds"));
+        }
+        match self.sink {
+            PixelSink::Png { ref mut writer, ref encoded, ref mut output, wide, ref mut odd } => {
+                if wide {
+                    let mut samples = Vec::with_capacity(n + 1);
+                    samples.extend(odd.take());
+                    samples.extend_from_slice(&buf[..n]);
+                    if samples.len() % 2 == 1 {
+                        *odd = samples.pop();
+                    }
+                    swap_samples(&mut samples);
+                    writer.write_all(&samples)?;
+                } else {
+                    writer.write_all(&buf[..n])?;
+                }
+                encoded.pass_on(output)?;
+            }
+            PixelSink::Buffered(ref mut data) => data.extend_from_slice(&buf[..n]),
+        }
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::crypto::{
+    generate_keypair,
+    generate_signing_keypair
+};
+use crate::error::Result;
+use log::info;
+use std::fs;
+use std::path::PathBuf;
+
+pub struct KeygenOptions {
+    /// Written as `<output>.key` (private) and `<output>.pub` (public)
+    pub output_path: PathBuf,
+    /// Ed25519 signing key pair instead of an X25519 one
+    pub signing: bool,
+}
+
+/// Write a new X25519 (or Ed25519) key pair as hex encoded key files
+pub fn run(opts: KeygenOptions) -> Result<()> {
+    let (secret, public) = if opts.signing {
+        generate_signing_keypair()
+    } else {
+        generate_keypair()
+    };
+    let secret_path = opts.output_path.with_extension("key");
+    let public_path = opts.output_path.with_extension("pub");
+
+    write_private(&secret_path, &hex::encode(secret))?;
+    info!("Private key saved to {:?}", secret_path);
+    fs::write(&public_path, format!("{}\n", hex::encode(public)))?;
+    info!("Public key saved to {:?}", public_path);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &PathBuf, key: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", key)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &PathBuf, key: &str) -> Result<()> {
+    fs::write(path, format!("{}\n", key))?;
+    Ok(())
+}
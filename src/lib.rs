@@ -1,6 +1,27 @@
+pub mod carrier;
+#[cfg(feature = "cli")]
 pub mod cli;
+pub mod compress;
 pub mod crypto;
 pub mod decrypt;
 pub mod encrypt;
+pub(crate) mod erasure;
+pub mod error;
 pub mod header;
-pub mod image_ops;
\ No newline at end of file
+pub(crate) mod image_ops;
+pub mod keygen;
+pub mod merge;
+pub(crate) mod parallel;
+pub mod rekey;
+pub mod shamir;
+pub mod slots;
+pub mod split;
+pub(crate) mod stream;
+pub mod verify;
+
+pub use error::Error;
+
+// The examples in the README are compiled and run as doc tests
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+struct ReadmeDoctests;
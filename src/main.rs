@@ -1,21 +1,27 @@
-mod cli;
-mod encrypt;
-mod decrypt;
-mod crypto;
-mod header;
-mod image_ops;
-mod split;
-mod merge;
-
+use libp2wviewer::{
+    cli,
+    crypto,
+    Error,
+    decrypt,
+    encrypt,
+    keygen,
+    merge,
+    rekey,
+    slots,
+    split,
+    verify
+};
 use cli::{
     Cli,
-    Commands
+    Commands,
+    SlotCommands
 };
 use clap::Parser;
 use log::{
     info,
     error
 };
+use std::path::Path;
 use std::process;
 
 fn main() {
@@ -30,58 +36,200 @@ fn main() {
     env_logger::init();
 
     match args.command {
-        Commands::Split { input, num_parts, delete_original } => {
+        Commands::Split { input, num_parts, threshold, alpha, delete_original } => {
             info!("Split command selected");
             let opts = split::SplitOptions {
-                input_path: input,
+                input_path: input.into(),
                 num_parts,
+                threshold,
+                alpha,
                 delete_original,
             };
             if let Err(e) = split::run(opts) {
-                error!("Split operation failed: {:?}", e);
-                process::exit(1);
+                error!("Split operation failed: {}", e);
+                process::exit(exit_code(&e));
             }
         }
 
-        Commands::Merge { inputs, output } => {
+        Commands::Merge { inputs, output, alpha } => {
             info!("Merge command selected");
             let opts = merge::MergeOptions {
-                input: inputs,
-                output: output,
+                input: inputs.into_iter().map(Into::into).collect(),
+                output: output.into(),
+                alpha,
             };
             if let Err(e) = merge::run(opts) {
-                error!("Merge operation failed: {:?}", e);
-                process::exit(1);
+                error!("Merge operation failed: {}", e);
+                process::exit(exit_code(&e));
             }
         }
 
-        Commands::Encrypt { input, output, password, password_file, split } => {
+        Commands::Encrypt { input, output, password, password_file, password_env, password_command, split, min_parts, recipient, sign_key, cipher, threads, raw, lossless, compress, carrier_shape, pad, kdf } => {
             info!("Encrypt command selected");
             let opts = encrypt::EncryptOptions {
                 input_path: input.into(),
                 output_path: output.into(),
-                password,
-                password_file,
-                split: Some(split),
+                key: crypto::KeyOptions { password, password_file, password_env, password_command }.source(),
+                recipients: recipient,
+                threads,
+                raw,
+                lossless,
+                params: encrypt::EncryptParams {
+                    split: Some(split),
+                    min_parts,
+                    kdf: kdf.params(),
+                    sign_key,
+                    cipher,
+                    compress,
+                    carrier_shape,
+                    pad,
+                },
             };
             if let Err(e) = encrypt::run(opts) {
-                error!("Encryption failed: {:?}", e);
-                process::exit(1);
+                error!("Encryption failed: {}", e);
+                process::exit(exit_code(&e));
             }
         }
 
-        Commands::Decrypt { input, output, password, password_file } => {
+        Commands::Decrypt { input, output, password, password_file, password_env, password_command, identity, threads } => {
             info!("Decrypt command selected");
+            let key = match identity {
+                Some(identity) => Some(Box::new(crypto::Identity(identity)) as Box<dyn crypto::KeySource>),
+                None => crypto::KeyOptions { password, password_file, password_env, password_command }.source(),
+            };
             let opts = decrypt::DecryptOptions {
                 input_path: input.into(),
                 output_path: output.into(),
-                password,
-                password_file,
+                key,
+                threads,
             };
-            if let Err(e) = decrypt::run(opts) {
-                error!("Decryption failed: {:?}", e);
-                process::exit(1);
+            match decrypt::run(opts) {
+                Ok(signature @ verify::SignatureStatus::Invalid(_)) => eprintln!("{}", signature),
+                Ok(signature) => println!("{}", signature),
+                Err(e) => {
+                    error!("Decryption failed: {}", e);
+                    process::exit(exit_code(&e));
+                }
             }
         }
+
+        Commands::Keygen { output, signing } => {
+            info!("Keygen command selected");
+            let opts = keygen::KeygenOptions {
+                output_path: output.into(),
+                signing,
+            };
+            if let Err(e) = keygen::run(opts) {
+                error!("Key generation failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+
+        Commands::Verify { input, signer } => {
+            info!("Verify command selected");
+            let opts = verify::VerifyOptions {
+                input_path: input.into(),
+                signer,
+            };
+            if let Err(e) = verify::run(opts) {
+                error!("Verification failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+
+        Commands::Rekey {
+            input,
+            output,
+            password,
+            password_file,
+            password_env,
+            password_command,
+            new_password,
+            new_password_file,
+            new_password_env,
+            new_password_command,
+            kdf
+        } => {
+            info!("Rekey command selected");
+            let opts = rekey::RekeyOptions {
+                input_path: input.into(),
+                output_path: output.map(Into::into),
+                key: crypto::KeyOptions { password, password_file, password_env, password_command }.source(),
+                new_key: crypto::KeyOptions {
+                    password: new_password,
+                    password_file: new_password_file,
+                    password_env: new_password_env,
+                    password_command: new_password_command,
+                }.source(),
+                kdf: kdf.params(),
+            };
+            if let Err(e) = rekey::run(opts) {
+                error!("Rekey failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+
+        Commands::Slot { command } => {
+            info!("Slot command selected");
+            let result = match command {
+                SlotCommands::Add {
+                    input,
+                    output,
+                    password,
+                    password_file,
+                    password_env,
+                    password_command,
+                    new_password,
+                    new_password_file,
+                    new_password_env,
+                    new_password_command,
+                    new_recipient,
+                    kdf
+                } => {
+                    let new_key = match new_recipient {
+                        Some(recipient) => Some(Box::new(crypto::Recipient(recipient)) as Box<dyn crypto::KeySource>),
+                        None => crypto::KeyOptions {
+                            password: new_password,
+                            password_file: new_password_file,
+                            password_env: new_password_env,
+                            password_command: new_password_command,
+                        }.source(),
+                    };
+                    slots::run_add(slots::SlotAddOptions {
+                        input_path: input.into(),
+                        output_path: output.map(Into::into),
+                        key: crypto::KeyOptions { password, password_file, password_env, password_command }.source(),
+                        new_key,
+                        kdf: kdf.params(),
+                    })
+                }
+                SlotCommands::Remove { input, output, password, password_file, password_env, password_command, slot } => {
+                    slots::run_remove(slots::SlotRemoveOptions {
+                        input_path: input.into(),
+                        output_path: output.map(Into::into),
+                        key: crypto::KeyOptions { password, password_file, password_env, password_command }.source(),
+                        slot,
+                    })
+                }
+                SlotCommands::List { input } => slots::run_list(Path::new(&input)),
+            };
+            if let Err(e) = result {
+                error!("Slot operation failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+}
+
+/// Exit code of a failed command, so scripts can tell a wrong key from a damaged carrier.
+/// 2 is left to clap for usage errors.
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Invalid(_) | Error::Other(_) => 1,
+        Error::WrongKey => 3,
+        Error::AuthFailed(_) => 4,
+        Error::BadMagic(_) | Error::UnsupportedVersion(_) => 5,
+        Error::MissingPart(_) | Error::Truncated(_) => 6,
+        Error::Io(_) | Error::Image(_) => 7,
     }
-}
\ No newline at end of file
+}
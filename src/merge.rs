@@ -1,49 +1,120 @@
+use crate::error::{
+    Error,
+    Result
+};
+use crate::shamir;
+use crate::split::{
+    opaque,
+    ShareHeader
+};
 use image::{
     DynamicImage,
     GenericImageView,
     ImageBuffer,
-    Rgba
+    Rgba,
+    RgbaImage
 };
-use std::path::Path;
+use log::info;
+use std::path::PathBuf;
 
 pub struct MergeOptions {
-    pub input: Vec<String>,
-    pub output: String,
+    pub input: Vec<PathBuf>,
+    pub output: PathBuf,
+    /// The parts were split with their alpha channel. Shamir shares record this themselves.
+    pub alpha: bool,
 }
 
-pub fn merge(parts: &[DynamicImage]) -> DynamicImage {
-    let first = &parts[0];
+/// Sum of n-of-n parts made by `split::split`, with the same `alpha` as they were split with
+pub fn merge(parts: &[DynamicImage], alpha: bool) -> Result<DynamicImage> {
+    let channels = if alpha { 4 } else { 3 };
+    let first = parts.first().ok_or_else(|| Error::Invalid("No part images found".to_string()))?;
     let (width, height) = first.dimensions();
+    if parts.iter().any(|img| img.dimensions() != (width, height)) {
+        return Err(Error::Invalid("All partial images must have the same size".to_string()));
+    }
     let mut result = ImageBuffer::new(width, height);
     for y in 0..height {
         for x in 0..width {
             let mut f_pix = [0u8; 4];
             for part in parts {
                 let part = part.get_pixel(x, y).0;
-                for i in 0..3 {
+                for i in 0..channels {
                     f_pix[i] = f_pix[i].wrapping_add(part[i]);
                 }
             }
-            f_pix[3] = 255;
+            if !alpha {
+                f_pix[3] = 255;
+            }
             
             result.put_pixel(x, y, Rgba(f_pix));
         }
     }
     
-    DynamicImage::ImageRgba8(result)
+    Ok(DynamicImage::ImageRgba8(result))
+}
+
+/// Image from Shamir shares made by `split::split_shares`, any `threshold` of them in any order
+pub fn merge_shares(parts: &[DynamicImage]) -> Result<DynamicImage> {
+    let mut shares = Vec::new();
+    for part in parts {
+        let header = ShareHeader::from_bytes(part.to_rgb8().as_raw())
+            .ok_or_else(|| Error::BadMagic("All parts must be shares made with split --threshold".to_string()))?;
+        shares.push((header, part));
+    }
+    let Some(&(first, first_part)) = shares.first() else {
+        return Err(Error::Invalid("No part images found".to_string()));
+    };
+    let (width, height) = first_part.dimensions();
+    if shares.iter().any(|(header, part)| (header.threshold, header.alpha, header.set_id) != (first.threshold, first.alpha, first.set_id) || part.dimensions() != (width, height)) {
+        return Err(Error::Invalid("The shares don't belong to the same image".to_string()));
+    }
+    shares.sort_by_key(|(header, _)| header.x);
+    shares.dedup_by_key(|(header, _)| header.x);
+    if shares.len() < first.threshold as usize {
+        return Err(Error::MissingPart(format!("{} different shares are needed, got {}", first.threshold, shares.len())));
+    }
+    let rows = first.rows(width);
+    if height <= rows {
+        return Err(Error::Truncated("Share too small".to_string()));
+    }
+    // Shares with alpha hold RGBA bytes, the others RGB
+    let samples: Vec<(u8, Vec<u8>)> = shares.iter()
+        .take(first.threshold as usize)
+        .map(|(header, part)| (header.x, if first.alpha { part.to_rgba8().into_raw() } else { part.to_rgb8().into_raw() }))
+        .collect();
+    let start = (width * rows) as usize * if first.alpha { 4 } else { 3 };
+    let ys: Vec<(u8, &[u8])> = samples.iter()
+        .map(|(x, bytes)| (*x, &bytes[start..]))
+        .collect();
+    let secret = shamir::combine(&ys);
+    let rgba = RgbaImage::from_raw(width, height - rows, if first.alpha { secret } else { opaque(secret) })
+        .ok_or_else(|| Error::Truncated("Share too small".to_string()))?;
+    Ok(DynamicImage::ImageRgba8(rgba))
 }
 
-pub fn run(opts: MergeOptions) -> Result<(), Box<dyn std::error::Error>> {
+/// Merge the parts given in any order: Shamir shares, or n-of-n parts named `<stem>.<n>.<ext>`
+pub fn run(opts: MergeOptions) -> Result<()> {
     if opts.input.is_empty() {
-        return Err("No path provided".into());
+        return Err(Error::Invalid("No path provided".to_string()));
+    }
+    info!("Merging {} parts into {:?}", opts.input.len(), opts.output);
+    // Shamir shares carry their x-coordinate, so any of them will do in any order
+    let first = image::open(&opts.input[0])?;
+    if ShareHeader::from_bytes(first.to_rgb8().as_raw()).is_some() {
+        let mut parts = vec![first];
+        for path in &opts.input[1..] {
+            parts.push(image::open(path)?);
+        }
+        let result = merge_shares(&parts)?;
+        result.save(&opts.output)?;
+        return Ok(());
     }
     let mut n_paths = Vec::new();
-    for path_str in &opts.input {
-        let path = Path::new(path_str);
+    for path in &opts.input {
         let stem = path.file_stem()
-            .ok_or("Invalid filename")?
+            .ok_or_else(|| Error::Invalid("Invalid filename".to_string()))?
             .to_str()
-            .ok_or("Invalid Unicode in output path")?;
+            .ok_or_else(|| Error::Invalid("Invalid Unicode in output path".to_string()))?;
         
         let num = if let Some(last_dot) = stem.rfind('.') {
             stem[last_dot + 1..].parse::<usize>().ok()
@@ -52,13 +123,13 @@ pub fn run(opts: MergeOptions) -> Result<(), Box<dyn std::error::Error>> {
         };
         
         if let Some(n) = num {
-            n_paths.push((n, path_str.clone()));
+            n_paths.push((n, path.clone()));
         }
     }
     n_paths.sort_by_key(|k| k.0);
     for (i, &(num, _)) in n_paths.iter().enumerate() {
         if num != i + 1 {
-            return Err(format!("Missing or incorrect numbering of parts. Expected: {}, found: {}", i + 1, num).into());
+            return Err(Error::MissingPart(format!("Missing or incorrect numbering of parts. Expected: {}, found: {}", i + 1, num)));
         }
     }
     let mut parts = Vec::new();
@@ -66,15 +137,7 @@ pub fn run(opts: MergeOptions) -> Result<(), Box<dyn std::error::Error>> {
         let image = image::open(path)?;
         parts.push(image);
     }
-    if let Some(first) = parts.first() {
-        let (width, height) = first.dimensions();
-        if parts.iter().any(|img| img.dimensions() != (width, height)) {
-            return Err("All partial images must have the same size".into());
-        }
-    } else {
-        return Err("No part images found".into());
-    }
-    let result = merge(&parts);
+    let result = merge(&parts, opts.alpha)?;
     result.save(&opts.output)?;
     
     Ok(())
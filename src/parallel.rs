@@ -0,0 +1,71 @@
+use anyhow::Result;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Run `f` on a pool of `threads` threads, on the global pool (one thread per core) for `None`.
+/// Without the `parallel` feature everything runs on the calling thread.
+#[cfg(feature = "parallel")]
+pub fn install<T, F>(threads: Option<usize>, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send,
+    T: Send,
+{
+    match threads {
+        Some(threads) => {
+            log::debug!("Using {} thread(s)", threads);
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build()?.install(f)
+        }
+        None => f(),
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn install<T, F>(threads: Option<usize>, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send,
+    T: Send,
+{
+    if threads.is_some_and(|t| t > 1) {
+        log::warn!("Built without the parallel feature, running on a single thread");
+    }
+    f()
+}
+
+/// Number of segments to process together, enough to keep every thread busy
+pub fn batch_size() -> usize {
+    #[cfg(feature = "parallel")]
+    return rayon::current_num_threads() * 4;
+    #[cfg(not(feature = "parallel"))]
+    return 1;
+}
+
+/// `items.iter().map(f).collect()`, the results stay in order
+pub fn map<T, U, F>(items: &[T], f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync + Send,
+{
+    #[cfg(feature = "parallel")]
+    return items.par_iter().map(f).collect();
+    #[cfg(not(feature = "parallel"))]
+    return items.iter().map(f).collect();
+}
+
+/// Call `f` for each row of `input` with the matching row of `output`
+pub fn rows<F>(input: &[u8], input_row: usize, output: &mut [u8], output_row: usize, f: F)
+where
+    F: Fn(&[u8], &mut [u8]) + Sync + Send,
+{
+    if input_row == 0 || output_row == 0 {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    output.par_chunks_mut(output_row)
+        .zip(input.par_chunks(input_row))
+        .for_each(|(out, row)| f(row, out));
+    #[cfg(not(feature = "parallel"))]
+    output.chunks_mut(output_row)
+        .zip(input.chunks(input_row))
+        .for_each(|(out, row)| f(row, out));
+}
@@ -0,0 +1,119 @@
+use crate::carrier::{
+    part_paths,
+    CarrierReader,
+    CarrierShape,
+    CarrierWriter
+};
+use crate::crypto::{
+    seal_slot,
+    unlock,
+    KdfParams,
+    KeySource
+};
+use crate::error::{
+    Error,
+    Result
+};
+use crate::header::Header;
+use log::info;
+use std::fs;
+use std::io::{
+    self,
+    Write
+};
+use std::path::{
+    Path,
+    PathBuf
+};
+
+pub struct RekeyOptions {
+    pub input_path: PathBuf,
+    /// Where to write the rekeyed image(s), `None` overwrites the input
+    pub output_path: Option<PathBuf>,
+    /// Key that unlocks one of the current slots
+    pub key: Option<Box<dyn KeySource>>,
+    /// Key that slot is rewrapped under
+    pub new_key: Option<Box<dyn KeySource>>,
+    pub kdf: KdfParams,
+}
+
+/// Rewrap the data key in the slot unlocked by `old` under `new`.
+/// The ciphertext doesn't depend on the key slots, so it stays valid as is.
+pub fn rekey(header: &Header, old: &dyn KeySource, new: &dyn KeySource, kdf: &KdfParams) -> Result<Header> {
+    if header.version < 4 {
+        return Err(Error::UnsupportedVersion(format!("Header version {} has no key slots, decrypt and encrypt the image again to change its key", header.version)));
+    }
+    let (slot, key) = unlock(old, &header.slots)?;
+    let mut header = header.clone();
+    header.slots[slot] = seal_slot(new, kdf, &key)?;
+    info!("Rewrapped key slot {}", slot);
+    Ok(header)
+}
+
+pub fn run(opts: RekeyOptions) -> Result<()> {
+    info!("Starting rekey for {:?}", opts.input_path);
+    let old = opts.key
+        .ok_or_else(|| Error::Invalid("No password or password file provided".to_string()))?;
+    let new = opts.new_key
+        .ok_or_else(|| Error::Invalid("No new password or new password file provided".to_string()))?;
+    let output_path = update_header(&opts.input_path, opts.output_path, |header| {
+        rekey(header, old.as_ref(), new.as_ref(), &opts.kdf)
+    })?;
+    info!("Rekey completed successfully: {:?}", output_path);
+    Ok(())
+}
+
+/// Replace the header of an encrypted image with the one returned by `update`.
+/// The ciphertext is streamed over unchanged, returns the path the image was written to.
+/// Without an output path the parts are overwritten, whatever they are named.
+pub(crate) fn update_header<F>(input_path: &Path, output_path: Option<PathBuf>, update: F) -> Result<PathBuf>
+where
+    F: FnOnce(&Header) -> Result<Header>,
+{
+    let mut carrier = CarrierReader::open(input_path)?;
+    let header = carrier.header().clone();
+    let new_header = update(&header)?;
+    let header_bytes = new_header.to_bytes()?;
+    let ciphertext_len = header.total_len()? - carrier.header_bytes().len() as u64;
+
+    let (output_path, paths) = match output_path {
+        Some(path) => {
+            let paths = part_paths(&path, carrier.part_count() as u32)?;
+            (path, paths)
+        }
+        None if carrier.parts().len() < carrier.part_count() => {
+            return Err(Error::MissingPart(format!("{} of {} parts are missing, give an output path to write all of them", carrier.part_count() - carrier.parts().len(), carrier.part_count())));
+        }
+        None => (input_path.to_path_buf(), carrier.parts()),
+    };
+    // The parts may be the ones being read, they are replaced once all are written
+    let temp_paths: Vec<PathBuf> = paths.iter().map(|p| p.with_extension("rekey.png")).collect();
+    let result = (|| -> Result<()> {
+        // Keep the size of a padded carrier
+        let mut writer = CarrierWriter::create_at(
+            header_bytes.len() as u64 + ciphertext_len,
+            header_bytes.len() as u64,
+            carrier.capacity(),
+            CarrierShape::Width(carrier.width()),
+            temp_paths.clone(),
+            carrier.min_parts(),
+        )?;
+        writer.write_all(&header_bytes)?;
+        let copied = io::copy(&mut carrier, &mut writer)?;
+        if copied != ciphertext_len {
+            return Err(Error::Truncated(format!("Carrier ended after {} of {} ciphertext bytes", copied, ciphertext_len)));
+        }
+        writer.finish()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        for path in &temp_paths {
+            let _ = fs::remove_file(path);
+        }
+        return Err(e);
+    }
+    for (temp, path) in temp_paths.iter().zip(&paths) {
+        fs::rename(temp, path)?;
+    }
+    Ok(output_path)
+}
@@ -0,0 +1,80 @@
+use crate::crypto::fill_random;
+
+/// Logarithms and powers of the generator 3 in GF(256) with the AES polynomial x^8 + x^4 + x^3 + x + 1
+const TABLES: ([u8; 256], [u8; 255]) = {
+    let mut log = [0u8; 256];
+    let mut exp = [0u8; 255];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        // x * 3 = x * 2 + x
+        x ^= x << 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11b;
+        }
+        i += 1;
+    }
+    (log, exp)
+};
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (log, exp) = &TABLES;
+    exp[(log[a as usize] as usize + log[b as usize] as usize) % 255]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    debug_assert!(b != 0, "division by zero in GF(256)");
+    if a == 0 {
+        return 0;
+    }
+    let (log, exp) = &TABLES;
+    exp[(log[a as usize] as usize + 255 - log[b as usize] as usize) % 255]
+}
+
+/// Shamir's secret sharing of every byte of `secret`: share `x` holds the value
+/// at `x` of a random polynomial of degree `threshold - 1` through the byte at 0,
+/// so any `threshold` of the shares give back the secret. Shares are returned
+/// for x = 1..=`shares`.
+pub fn share(secret: &[u8], threshold: u8, shares: u8) -> Vec<Vec<u8>> {
+    debug_assert!(threshold >= 2, "a threshold of {} shares nothing", threshold);
+    let degree = threshold as usize - 1;
+    let mut coefficients = vec![0u8; secret.len() * degree];
+    fill_random(&mut coefficients);
+    (1..=shares)
+        .map(|x| {
+            secret.iter()
+                .zip(coefficients.chunks_exact(degree))
+                .map(|(&byte, coefficients)| {
+                    // Horner's rule, highest coefficient first
+                    let y = coefficients.iter().rev().fold(0, |y, &c| mul(y, x) ^ c);
+                    mul(y, x) ^ byte
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Secret from `threshold` or more shares with distinct x-coordinates,
+/// by Lagrange interpolation at 0
+pub fn combine(shares: &[(u8, &[u8])]) -> Vec<u8> {
+    let len = shares.iter().map(|(_, ys)| ys.len()).min().unwrap_or(0);
+    let basis: Vec<u8> = shares.iter()
+        .map(|&(xj, _)| {
+            shares.iter()
+                .filter(|&&(xm, _)| xm != xj)
+                .fold(1, |b, &(xm, _)| mul(b, div(xm, xm ^ xj)))
+        })
+        .collect();
+    (0..len)
+        .map(|i| {
+            shares.iter()
+                .zip(&basis)
+                .fold(0, |secret, (&(_, ys), &b)| secret ^ mul(b, ys[i]))
+        })
+        .collect()
+}
@@ -0,0 +1,130 @@
+use crate::crypto::{
+    seal_slot,
+    unlock,
+    KdfParams,
+    KeySource
+};
+use crate::carrier::CarrierReader;
+use crate::error::{
+    Error,
+    Result
+};
+use crate::header::{
+    Header,
+    KeySlot
+};
+use crate::rekey::update_header;
+use log::info;
+use std::path::{
+    Path,
+    PathBuf
+};
+
+pub struct SlotAddOptions {
+    pub input_path: PathBuf,
+    /// Where to write the updated image(s), `None` overwrites the input
+    pub output_path: Option<PathBuf>,
+    /// Key that unlocks one of the current slots
+    pub key: Option<Box<dyn KeySource>>,
+    /// Key to add, a password, key file or `Recipient` among others
+    pub new_key: Option<Box<dyn KeySource>>,
+    pub kdf: KdfParams,
+}
+
+pub struct SlotRemoveOptions {
+    pub input_path: PathBuf,
+    /// Where to write the updated image(s), `None` overwrites the input
+    pub output_path: Option<PathBuf>,
+    /// Key that unlocks one of the current slots
+    pub key: Option<Box<dyn KeySource>>,
+    /// Slot to remove, the one unlocked by `key` if `None`
+    pub slot: Option<usize>,
+}
+
+/// Add a key slot for `new`, `existing` has to unlock one of the current slots
+pub fn add_slot(header: &Header, existing: &dyn KeySource, new: &dyn KeySource, kdf: &KdfParams) -> Result<Header> {
+    if header.version < 4 {
+        return Err(Error::UnsupportedVersion(format!("Header version {} has no key slots, decrypt and encrypt the image again to add a key", header.version)));
+    }
+    let (_slot, key) = unlock(existing, &header.slots)?;
+    let mut header = header.clone();
+    header.slots.push(seal_slot(new, kdf, &key)?);
+    info!("Added key slot {}", header.slots.len() - 1);
+    Ok(header)
+}
+
+/// Remove a key slot, `existing` has to unlock one of the current slots.
+/// Without an index the slot unlocked by `existing` is removed.
+pub fn remove_slot(header: &Header, existing: &dyn KeySource, index: Option<usize>) -> Result<Header> {
+    if header.version < 4 {
+        return Err(Error::UnsupportedVersion(format!("Header version {} has no key slots", header.version)));
+    }
+    let (unlocked, _key) = unlock(existing, &header.slots)?;
+    let index = index.unwrap_or(unlocked);
+    if index >= header.slots.len() {
+        return Err(Error::Invalid(format!("No key slot {} (the image has {})", index, header.slots.len())));
+    }
+    if header.slots.len() == 1 {
+        return Err(Error::Invalid("Refusing to remove the last key slot, the image could never be decrypted again".to_string()));
+    }
+    let mut header = header.clone();
+    header.slots.remove(index);
+    info!("Removed key slot {}", index);
+    Ok(header)
+}
+
+/// One line description of a key slot, without anything secret
+pub fn describe(slot: &KeySlot) -> String {
+    match slot {
+        KeySlot::Password { kdf, .. } => format!(
+            "password ({:?}, m_cost={}, t_cost={}, p_cost={}, output_len={})",
+            kdf.algorithm, kdf.m_cost, kdf.t_cost, kdf.p_cost, kdf.output_len
+        ),
+        KeySlot::KeyFile { .. } => "key file".to_string(),
+        KeySlot::X25519 { .. } => "x25519 recipient".to_string(),
+    }
+}
+
+/// Key slots of an encrypted image, doesn't need a password
+pub fn list(input_path: &Path) -> Result<Vec<KeySlot>> {
+    let carrier = CarrierReader::open(input_path)?;
+    let header = carrier.header();
+    if header.version < 4 {
+        return Err(Error::UnsupportedVersion(format!("Header version {} has no key slots", header.version)));
+    }
+    Ok(header.slots.clone())
+}
+
+pub fn run_add(opts: SlotAddOptions) -> Result<()> {
+    info!("Adding key slot to {:?}", opts.input_path);
+    let existing = existing_key(opts.key)?;
+    let new = opts.new_key
+        .ok_or_else(|| Error::Invalid("No new password, new password file or new recipient provided".to_string()))?;
+    let output_path = update_header(&opts.input_path, opts.output_path, |header| {
+        add_slot(header, existing.as_ref(), new.as_ref(), &opts.kdf)
+    })?;
+    info!("Key slot added: {:?}", output_path);
+    Ok(())
+}
+
+pub fn run_remove(opts: SlotRemoveOptions) -> Result<()> {
+    info!("Removing key slot from {:?}", opts.input_path);
+    let existing = existing_key(opts.key)?;
+    let output_path = update_header(&opts.input_path, opts.output_path, |header| {
+        remove_slot(header, existing.as_ref(), opts.slot)
+    })?;
+    info!("Key slot removed: {:?}", output_path);
+    Ok(())
+}
+
+/// Key source that unlocks one of the current slots
+fn existing_key(key: Option<Box<dyn KeySource>>) -> Result<Box<dyn KeySource>> {
+    key.ok_or_else(|| Error::Invalid("No password or password file provided".to_string()))
+}
+
+pub fn run_list(input_path: &Path) -> Result<()> {
+    for (i, slot) in list(input_path)?.iter().enumerate() {
+        println!("{}: {}", i, describe(slot));
+    }
+    Ok(())
+}
@@ -1,19 +1,140 @@
+use crate::crypto::fill_random;
+use crate::error::{
+    Error,
+    Result
+};
+use crate::shamir;
 use image::{
     DynamicImage,
     GenericImageView,
     ImageBuffer,
-    Rgba
+    ImageFormat,
+    Rgba,
+    RgbaImage
 };
+use log::info;
 use rand::Rng;
-use std::path::Path;
+use std::path::{
+    Path,
+    PathBuf
+};
 
 pub struct SplitOptions {
-    pub input_path: String,
+    pub input_path: PathBuf,
     pub num_parts: u32,
+    /// Shares needed to merge, all of them if `None`
+    pub threshold: Option<u32>,
+    /// Share the alpha channel too, otherwise the parts and the merged image are opaque
+    pub alpha: bool,
     pub delete_original: bool,
 }
 
-pub fn split(image: &DynamicImage, num_parts: u32) -> Vec<DynamicImage> {
+const SHARE_MAGIC: &[u8; 4] = b"P2WS";
+/// Magic, version, x-coordinate and threshold
+const SHARE_HEADER_LEN: usize = 7;
+/// Version 2 adds a flags byte
+const FLAGGED_SHARE_HEADER_LEN: usize = SHARE_HEADER_LEN + 1;
+/// Version 3 adds the set ID
+const SET_SHARE_HEADER_LEN: usize = FLAGGED_SHARE_HEADER_LEN + 16;
+const FLAG_ALPHA: u8 = 1;
+
+/// Stored in the RGB bytes of the row(s) on top of a Shamir share,
+/// so `merge` needs nothing but the shares
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareHeader {
+    pub x: u8,
+    pub threshold: u8,
+    /// The share holds RGBA bytes instead of RGB
+    pub alpha: bool,
+    /// Random ID shared by all shares of one split, `None` for shares written before it
+    pub set_id: Option<[u8; 16]>,
+}
+
+impl ShareHeader {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = SHARE_MAGIC.to_vec();
+        match self.set_id {
+            Some(set_id) => {
+                bytes.extend_from_slice(&[3, self.x, self.threshold, self.flags()]);
+                bytes.extend_from_slice(&set_id);
+            }
+            None if self.alpha => bytes.extend_from_slice(&[2, self.x, self.threshold, self.flags()]),
+            None => bytes.extend_from_slice(&[1, self.x, self.threshold]),
+        }
+        bytes
+    }
+
+    fn flags(self) -> u8 {
+        if self.alpha { FLAG_ALPHA } else { 0 }
+    }
+
+    /// Header at the start of the RGB bytes of an image, `None` if it isn't a share
+    pub fn from_bytes(rgb: &[u8]) -> Option<Self> {
+        if rgb.len() < SHARE_HEADER_LEN || rgb[..4] != *SHARE_MAGIC {
+            return None;
+        }
+        let (flags, set_id) = match rgb[4] {
+            1 => (0, None),
+            2 if rgb.len() >= FLAGGED_SHARE_HEADER_LEN => (rgb[7], None),
+            3 if rgb.len() >= SET_SHARE_HEADER_LEN => (rgb[7], rgb[8..SET_SHARE_HEADER_LEN].try_into().ok()),
+            _ => return None,
+        };
+        Some(Self { x: rgb[5], threshold: rgb[6], alpha: flags & FLAG_ALPHA != 0, set_id })
+    }
+
+    /// Rows on top of a share of this width that hold the header
+    pub fn rows(&self, width: u32) -> u32 {
+        let len = match (self.set_id, self.alpha) {
+            (Some(_), _) => SET_SHARE_HEADER_LEN,
+            (None, true) => FLAGGED_SHARE_HEADER_LEN,
+            (None, false) => SHARE_HEADER_LEN,
+        };
+        (len as u32).div_ceil(width.max(1) * 3)
+    }
+}
+
+/// Shamir shares of the RGB(A) bytes, any `threshold` of the `num_parts` images give back the image.
+/// Each share is as wide as the image and has the share header on top.
+pub fn split_shares(image: &DynamicImage, threshold: u32, num_parts: u32, alpha: bool) -> Result<Vec<DynamicImage>> {
+    if num_parts > 255 || threshold < 2 || threshold > num_parts {
+        return Err(Error::Invalid(format!("Need 2 <= threshold <= parts <= 255, got {} of {}", threshold, num_parts)));
+    }
+    let (threshold, num_parts) = (threshold as u8, num_parts as u8);
+    let (width, height) = image.dimensions();
+    let secret = if alpha { image.to_rgba8().into_raw() } else { image.to_rgb8().into_raw() };
+    let mut set_id = [0u8; 16];
+    fill_random(&mut set_id);
+    let rows = ShareHeader { x: 0, threshold, alpha, set_id: Some(set_id) }.rows(width);
+    Ok(shamir::share(&secret, threshold, num_parts).into_iter()
+        .zip(1..=num_parts)
+        .map(|(ys, x)| {
+            let mut header = vec![0u8; (width * rows * 3) as usize];
+            fill_random(&mut header);
+            let header_bytes = ShareHeader { x, threshold, alpha, set_id: Some(set_id) }.to_bytes();
+            header[..header_bytes.len()].copy_from_slice(&header_bytes);
+            // The header rows are opaque, so it can be read from the RGB bytes
+            let mut bytes = opaque(header);
+            bytes.extend(if alpha { ys } else { opaque(ys) });
+            let share = RgbaImage::from_raw(width, height + rows, bytes).expect("share has the size of the image");
+            DynamicImage::ImageRgba8(share)
+        })
+        .collect())
+}
+
+/// RGBA bytes of RGB bytes with alpha 255
+pub(crate) fn opaque(rgb: Vec<u8>) -> Vec<u8> {
+    rgb.chunks_exact(3)
+        .flat_map(|px| [px[0], px[1], px[2], 255])
+        .collect()
+}
+
+/// n-of-n split: all parts but the last are random, the last one is the image minus all of them.
+/// Alpha is only shared if `alpha` is set, otherwise the parts are opaque.
+pub fn split(image: &DynamicImage, num_parts: u32, alpha: bool) -> Result<Vec<DynamicImage>> {
+    if num_parts == 0 {
+        return Err(Error::Invalid("Can't split into 0 parts".to_string()));
+    }
+    let channels = if alpha { 4 } else { 3 };
     let (width, height) = image.dimensions();
     let mut rng = rand::rng();
     let mut parts: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> = vec![];
@@ -25,7 +146,7 @@ pub fn split(image: &DynamicImage, num_parts: u32) -> Vec<DynamicImage> {
                     rng.random::<u8>(),
                     rng.random::<u8>(),
                     rng.random::<u8>(),
-                    255,
+                    if alpha { rng.random::<u8>() } else { 255 },
                 ]);
                 part.put_pixel(x, y, random_pixel);
             }
@@ -40,40 +161,45 @@ pub fn split(image: &DynamicImage, num_parts: u32) -> Vec<DynamicImage> {
             f_pixel.copy_from_slice(&original.0);
             for part in &parts {
                 let part_pixel = part.get_pixel(x, y).0;
-                for i in 0..3 {
+                for i in 0..channels {
                     f_pixel[i] = f_pixel[i].wrapping_sub(part_pixel[i]);
                 }
             }
-            f_pixel[3] = 255;
+            if !alpha {
+                f_pixel[3] = 255;
+            }
             
             f_part.put_pixel(x, y, Rgba(f_pixel));
         }
     }
     let mut result = parts.into_iter()
-        .map(|img| DynamicImage::ImageRgba8(img))
+        .map(DynamicImage::ImageRgba8)
         .collect::<Vec<_>>();
     result.push(DynamicImage::ImageRgba8(f_part));
     
-    result
+    Ok(result)
 }
 
-pub fn run(opts: SplitOptions) -> Result<(), Box<dyn std::error::Error>> {
+/// Split the image into parts named `<stem>.<n>.png` next to it.
+/// Always PNG, a lossy format would destroy the parts.
+pub fn run(opts: SplitOptions) -> Result<()> {
+    info!("Splitting {:?} into {} parts", opts.input_path, opts.num_parts);
     let img = image::open(&opts.input_path)?;
-    let parts = split(&img, opts.num_parts);
-    let path = Path::new(&opts.input_path);
+    let parts = match opts.threshold {
+        Some(threshold) => split_shares(&img, threshold, opts.num_parts, opts.alpha)?,
+        None => split(&img, opts.num_parts, opts.alpha)?,
+    };
+    let path = &opts.input_path;
     let output_dir = path.parent().unwrap_or_else(|| Path::new("."));
     let stem = path.file_stem()
-        .ok_or("Can't extract filename")?
-        .to_str()
-        .ok_or("Invalid filename")?;
-    let extension = path.extension()
-        .ok_or("No Extension")?
+        .ok_or_else(|| Error::Invalid("Can't extract filename".to_string()))?
         .to_str()
-        .ok_or("Invalid Unicode in output path")?;
+        .ok_or_else(|| Error::Invalid("Invalid filename".to_string()))?;
     for (i, part) in parts.iter().enumerate() {
-        let output_filename = format!("{}.{}.{}", stem, i + 1, extension);
+        let output_filename = format!("{}.{}.png", stem, i + 1);
         let output_path = output_dir.join(output_filename);
-        part.save(&output_path)?;
+        part.save_with_format(&output_path, ImageFormat::Png)?;
+        info!("Saved part {} to {:?}", i + 1, output_path);
     }
     if opts.delete_original {
         std::fs::remove_file(&opts.input_path)?;
@@ -0,0 +1,172 @@
+use crate::crypto::{
+    CipherSuite,
+    SignatureDigest
+};
+use crate::error::Error;
+use crate::header::Header;
+use crate::parallel;
+use anyhow::{
+    Result,
+    anyhow,
+    bail
+};
+use log::{
+    debug,
+    info
+};
+use std::io::{
+    Read,
+    Write
+};
+
+/// Plaintext bytes per segment written by `encrypt`
+pub const SEGMENT_SIZE: u32 = 64 * 1024;
+
+/// Every segment carries its own auth tag
+const TAG_SIZE: u64 = 16;
+
+/// Number of segments for `plaintext_len` bytes, an empty payload still has one
+pub fn segment_count(plaintext_len: u64, segment_size: u32) -> u64 {
+    plaintext_len.div_ceil(segment_size as u64).max(1)
+}
+
+/// Length of the segmented ciphertext, the plaintext plus one tag per segment
+pub fn ciphertext_len(plaintext_len: u64, segment_size: u32) -> Result<u64> {
+    plaintext_len.checked_add(segment_count(plaintext_len, segment_size) * TAG_SIZE)
+        .ok_or_else(|| anyhow!("Payload too large ({} bytes)", plaintext_len))
+}
+
+/// Inverse of `ciphertext_len`, fails for lengths `encrypt` can't produce
+pub fn plaintext_len(ciphertext_len: u64, segment_size: u32) -> Result<u64> {
+    let sealed = segment_size as u64 + TAG_SIZE;
+    let segments = ciphertext_len.div_ceil(sealed).max(1);
+    let last = ciphertext_len.saturating_sub((segments - 1) * sealed);
+    if last < TAG_SIZE || (last == TAG_SIZE && segments > 1) {
+        bail!("Invalid length {} for a segmented payload", ciphertext_len);
+    }
+    Ok(ciphertext_len - segments * TAG_SIZE)
+}
+
+/// STREAM construction: the payload is cut into segments that are encrypted
+/// and authenticated one by one, so only one segment has to be in memory.
+/// Segment nonces are the header nonce with its last 5 bytes replaced by a
+/// big endian counter and a flag marking the last segment. Reordering,
+/// dropping or truncating segments therefore fails authentication.
+/// Every file has its own random data key, so the shortened random part of
+/// the nonce can't collide with another file.
+pub struct Stream {
+    cipher: CipherSuite,
+    key: [u8; 32],
+    nonce: Vec<u8>,
+    aad: Vec<u8>,
+    segment_size: u32,
+}
+
+impl Stream {
+    /// Stream for the payload of `header`, which needs a stream record
+    pub fn new(header: &Header, key: &[u8; 32]) -> Result<Self> {
+        let segment_size = header.segment_size
+            .ok_or_else(|| Error::Invalid("Header has no stream record".to_string()))?;
+        Ok(Self {
+            cipher: header.cipher,
+            key: *key,
+            nonce: header.nonce.clone(),
+            aad: header.aad()?,
+            segment_size,
+        })
+    }
+
+    pub fn segment_size(&self) -> u32 {
+        self.segment_size
+    }
+
+    fn segment_nonce(&self, index: u64, last: bool) -> Result<Vec<u8>> {
+        let counter = u32::try_from(index).map_err(|_| anyhow!("Too many segments"))?;
+        let mut nonce = self.nonce.clone();
+        let n = nonce.len();
+        nonce[n - 5..n - 1].copy_from_slice(&counter.to_be_bytes());
+        nonce[n - 1] = last as u8;
+        Ok(nonce)
+    }
+
+    /// Encrypt `plaintext_len` bytes from `reader` and write the sealed segments to `writer`.
+    /// Segments are read in batches that are sealed in parallel with the `parallel` feature.
+    pub fn encrypt<R: Read, W: Write>(&self, plaintext_len: u64, mut reader: R, mut writer: W) -> Result<()> {
+        let segments = segment_count(plaintext_len, self.segment_size);
+        debug!("Encrypting {} bytes in {} segment(s) with {:?}", plaintext_len, segments, self.cipher);
+        let batch_size = parallel::batch_size() as u64;
+        let mut remaining = plaintext_len;
+        let mut index = 0;
+        while index < segments {
+            let mut batch = Vec::new();
+            for i in index..segments.min(index + batch_size) {
+                let len = remaining.min(self.segment_size as u64);
+                let mut buf = Vec::with_capacity(len as usize);
+                (&mut reader).take(len).read_to_end(&mut buf)?;
+                if buf.len() as u64 != len {
+                    bail!("Input ended after {} of {} bytes", plaintext_len - remaining + buf.len() as u64, plaintext_len);
+                }
+                remaining -= len;
+                batch.push((i, buf));
+            }
+            index += batch.len() as u64;
+            let sealed = parallel::map(&batch, |(i, buf)| {
+                let nonce = self.segment_nonce(*i, *i + 1 == segments)?;
+                self.cipher.seal(&self.key, &nonce, buf, &self.aad)
+            });
+            for segment in sealed {
+                writer.write_all(&segment?)?;
+            }
+        }
+        if reader.read(&mut [0u8; 1])? != 0 {
+            bail!("Input is longer than {} bytes", plaintext_len);
+        }
+        info!("Encryption successful, {} segment(s)", segments);
+        Ok(())
+    }
+
+    /// Decrypt `ciphertext_len` bytes of sealed segments from `reader`. Each
+    /// segment is written to `writer` only after it was authenticated, and the
+    /// ciphertext is fed to `digest` to check a signature on the way.
+    /// Returns the plaintext length.
+    pub fn decrypt<R: Read, W: Write>(
+        &self,
+        ciphertext_len: u64,
+        mut reader: R,
+        mut writer: W,
+        mut digest: Option<&mut SignatureDigest>,
+    ) -> Result<u64> {
+        let plaintext_len = plaintext_len(ciphertext_len, self.segment_size)?;
+        let segments = segment_count(plaintext_len, self.segment_size);
+        debug!("Decrypting {} segment(s) with {:?}", segments, self.cipher);
+        let batch_size = parallel::batch_size() as u64;
+        let mut remaining = ciphertext_len;
+        let mut index = 0;
+        while index < segments {
+            let mut batch = Vec::new();
+            for i in index..segments.min(index + batch_size) {
+                let len = remaining.min(self.segment_size as u64 + TAG_SIZE);
+                let mut buf = Vec::with_capacity(len as usize);
+                (&mut reader).take(len).read_to_end(&mut buf)?;
+                if buf.len() as u64 != len {
+                    bail!(Error::Truncated(format!("Ciphertext truncated in segment {}", i)));
+                }
+                remaining -= len;
+                if let Some(ref mut digest) = digest {
+                    digest.update(&buf);
+                }
+                batch.push((i, buf));
+            }
+            index += batch.len() as u64;
+            let opened = parallel::map(&batch, |(i, buf)| {
+                let nonce = self.segment_nonce(*i, *i + 1 == segments)?;
+                self.cipher.open(&self.key, &nonce, buf, &self.aad)
+            });
+            for segment in opened {
+                writer.write_all(&segment?)?;
+            }
+        }
+        info!("Decryption successful, length {}", plaintext_len);
+        Ok(plaintext_len)
+    }
+}
@@ -0,0 +1,88 @@
+use crate::carrier::CarrierReader;
+use crate::crypto::{
+    read_key_file,
+    signature_message,
+    verify_signature,
+    SignatureDigest
+};
+use crate::error::{
+    Error,
+    Result
+};
+use crate::header::Header;
+use log::info;
+use std::io;
+use std::path::PathBuf;
+
+pub struct VerifyOptions {
+    pub input_path: PathBuf,
+    /// Public key file the image has to be signed with
+    pub signer: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Valid signature by this public key
+    Valid([u8; 32]),
+    /// The image was modified after signing, or the signature is forged
+    Invalid([u8; 32]),
+}
+
+/// Check the signature of an encrypted image, `ciphertext` is everything after the header
+pub fn check(header: &Header, ciphertext: &[u8]) -> Result<SignatureStatus> {
+    let aad = header.aad()?;
+    Ok(status(header, || signature_message(&aad, &header.auth_tag, ciphertext)))
+}
+
+/// Same as `check`, with the ciphertext already fed to `digest`
+pub(crate) fn check_digest(header: &Header, digest: SignatureDigest) -> SignatureStatus {
+    status(header, || digest.message())
+}
+
+fn status<F: FnOnce() -> Vec<u8>>(header: &Header, message: F) -> SignatureStatus {
+    let Some(ref signature) = header.signature else {
+        return SignatureStatus::Unsigned;
+    };
+    if verify_signature(signature, &message()) {
+        SignatureStatus::Valid(signature.signer)
+    } else {
+        SignatureStatus::Invalid(signature.signer)
+    }
+}
+
+impl std::fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unsigned => write!(f, "Image is not signed"),
+            Self::Valid(signer) => write!(f, "Valid signature by {}", hex::encode(signer)),
+            Self::Invalid(signer) => write!(f, "INVALID signature claiming to be by {}", hex::encode(signer)),
+        }
+    }
+}
+
+pub fn run(opts: VerifyOptions) -> Result<()> {
+    info!("Verifying signature of {:?}", opts.input_path);
+    let mut carrier = CarrierReader::open(&opts.input_path)?;
+    let header = carrier.header().clone();
+    if header.signature.is_none() {
+        return Err(Error::Invalid("Image is not signed".to_string()));
+    }
+    // The ciphertext is hashed as it is read, it's never all in memory
+    let mut digest = SignatureDigest::new(&header.aad()?, &header.auth_tag);
+    io::copy(&mut carrier, &mut digest)?;
+    let status = check_digest(&header, digest);
+    match status {
+        SignatureStatus::Unsigned => return Err(Error::Invalid("Image is not signed".to_string())),
+        SignatureStatus::Invalid(signer) => return Err(Error::AuthFailed(format!("Invalid signature claiming to be by {}", hex::encode(signer)))),
+        SignatureStatus::Valid(signer) => {
+            if let Some(ref expected) = opts.signer {
+                if read_key_file(expected)? != signer {
+                    return Err(Error::AuthFailed(format!("Valid signature, but by {} instead of the expected signer", hex::encode(signer))));
+                }
+            }
+            println!("Valid signature by {}", hex::encode(signer));
+        }
+    }
+    Ok(())
+}
@@ -4,6 +4,97 @@ RED='\033[0;31m'
 YELLOW='\033[0;33m'
 BLUE='\033[0;34m'
 N='\033[0m'
+# Peak memory of a command in KiB, its output goes to stderr
+peak_kib() {
+    python3 -c '
+import resource, subprocess, sys
+code = subprocess.run(sys.argv[1:], stdout=sys.stderr).returncode
+peak = resource.getrusage(resource.RUSAGE_CHILDREN).ru_maxrss
+print(peak // 1024 if sys.platform == "darwin" else peak)
+sys.exit(code)' "$@"
+}
+# SHA-256 of a file
+sha256() {
+    if command -v sha256sum > /dev/null; then
+        sha256sum "$1" | cut -d " " -f 1
+    else
+        shasum -a 256 "$1" | cut -d " " -f 1
+    fi
+}
+# Encrypt and decrypt every image in input/colors, the decrypted PNGs must be
+# identical and the other files have the same pixels, so the color type and
+# the samples are kept
+color_round_trip() {
+    for img in input/colors/*; do
+        local name=$(basename "$img")
+        rm -f output/test28/encrypted*
+        $exe encrypt -i "$img" -o output/test28/encrypted.png -s 2 -p "p2w" -vvv \
+            && $exe decrypt -i output/test28 -o "output/test28/$name" -p "p2w" -vvv \
+            || return 1
+        case "$img" in
+            *.png) cmp "$img" "output/test28/$name" || return 1 ;;
+            *) same_pixels "$img" "output/test28/$name" || return 1 ;;
+        esac
+    done
+}
+# Whether two images have the same pixels. Encoders of formats other than PNG
+# don't write the same bytes in every version, so both images are written as
+# PNG by the same build. 32-bit floats are compared as the 16-bit samples PNG holds.
+same_pixels() {
+    local dir=output/pixels
+    rm -rf "$dir" && mkdir -p "$dir"
+    $exe encrypt -i "$1" -o "$dir/a.png" -p "p2w" -vvv \
+        && $exe decrypt -i "$dir/a.png" -o "$dir/a_pixels.png" -p "p2w" -vvv \
+        && $exe encrypt -i "$2" -o "$dir/b.png" -p "p2w" -vvv \
+        && $exe decrypt -i "$dir/b.png" -o "$dir/b_pixels.png" -p "p2w" -vvv \
+        && cmp "$dir/a_pixels.png" "$dir/b_pixels.png"
+}
+# Width and height of a PNG, from its IHDR chunk
+png_size() {
+    od -An -tu1 -j16 -N8 "$1" | awk '{ print $1 * 16777216 + $2 * 65536 + $3 * 256 + $4, $5 * 16777216 + $6 * 65536 + $7 * 256 + $8 }'
+}
+# Whether a PNG is square, up to the last row
+is_square() {
+    local size=($(png_size "$1"))
+    [ $((size[0] - size[1])) -ge 0 ] && [ $((size[0] - size[1])) -le 1 ]
+}
+# Pixel bytes of an 8 or 16-bit PNG. png_pixels FILE prints their SHA-256,
+# png_pixels FILE OFFSET HEX overwrites the bytes at OFFSET, e.g. in a carrier
+png_pixels() {
+    python3 -c '
+import hashlib, struct, sys, zlib
+path = sys.argv[1]
+data = open(path, "rb").read()
+pos, chunks = 8, []
+while pos < len(data):
+    n, kind = struct.unpack(">I4s", data[pos:pos + 8])
+    chunks.append((kind, data[pos + 8:pos + 8 + n]))
+    pos += 12 + n
+ihdr = chunks[0][1]
+width, height, depth, color = struct.unpack(">IIBB", ihdr[:10])
+bpp = {0: 1, 2: 3, 4: 2, 6: 4}[color] * depth // 8
+raw = zlib.decompress(b"".join(c for k, c in chunks if k == b"IDAT"))
+stride, rows, prev = width * bpp, [], bytearray(width * bpp)
+for y in range(height):
+    f, row = raw[y * (stride + 1)], bytearray(raw[y * (stride + 1) + 1:(y + 1) * (stride + 1)])
+    for i in range(stride):
+        a = row[i - bpp] if i >= bpp else 0
+        b, c = prev[i], prev[i - bpp] if i >= bpp else 0
+        p = a + b - c
+        pred = [0, a, b, (a + b) // 2, a if abs(p - a) <= min(abs(p - b), abs(p - c)) else b if abs(p - b) <= abs(p - c) else c][f]
+        row[i] = (row[i] + pred) & 255
+    rows.append(row)
+    prev = row
+pixels = b"".join(rows)
+if len(sys.argv) < 4:
+    print(hashlib.sha256(pixels).hexdigest())
+    sys.exit()
+offset, new = int(sys.argv[2]), bytes.fromhex(sys.argv[3])
+pixels = pixels[:offset] + new + pixels[offset + len(new):]
+idat = zlib.compress(b"".join(b"\0" + pixels[y * stride:(y + 1) * stride] for y in range(height)))
+chunk = lambda k, c: struct.pack(">I", len(c)) + k + c + struct.pack(">I", zlib.crc32(k + c))
+open(path, "wb").write(data[:8] + chunk(b"IHDR", ihdr) + chunk(b"IDAT", idat) + chunk(b"IEND", b""))' "$@"
+}
 exec() {
     local num=$1
     local des=$2
@@ -20,7 +111,7 @@ exec() {
 }
 rm -rf output
 mkdir -p output
-for i in {1..10}; do
+for i in {1..41}; do
     mkdir -p "output/test$i"
 done
 mkdir -p "output/errors"
@@ -92,6 +183,178 @@ cp output/test7/encrypted_split.3.png output/test10/
 exec 10 "Decryption of split encrypted file" \
     "$exe decrypt -i output/test10 -o output/test10/decrypted_split.png -p \"p2w\" -vvv"
 
+cp input/test.png output/test11/test.png
+exec 11 "Encryption with custom Argon2 parameters" \
+    "$exe encrypt -i output/test11/test.png -o output/test11/encrypted_kdf.png -p \"p2w\" --kdf-algorithm argon2i --kdf-m-cost 8192 --kdf-t-cost 3 --kdf-parallelism 2 -vvv"
+
+cp output/test11/encrypted_kdf.png output/test12/
+exec 12 "Decryption with parameters from the header" \
+    "$exe decrypt -i output/test12/encrypted_kdf.png -o output/test12/decrypted_kdf.png -p \"p2w\" -vvv"
+
+cp output/test5/encrypted.png output/test13/
+exec 13 "Rekey from password to key file" \
+    "$exe rekey -i output/test13/encrypted.png -o output/test13/rekeyed.png -p \"p2w\" --new-password-file input/keyfile -vvv && $exe decrypt -i output/test13/rekeyed.png -o output/test13/decrypted.png --password-file input/keyfile -vvv"
+
+cp output/test7/encrypted_split.*.png output/test14/
+exec 14 "Rekey split parts in place" \
+    "$exe rekey -i output/test14 -p \"p2w\" --new-password \"w2p\" -vvv && $exe decrypt -i output/test14 -o output/test14/decrypted.png -p \"w2p\" -vvv"
+
+cp output/test5/encrypted.png output/test15/
+exec 15 "Add a key slot and decrypt with it" \
+    "$exe slot add -i output/test15/encrypted.png -p \"p2w\" --new-password-file input/keyfile -vvv && $exe slot list -i output/test15/encrypted.png && $exe decrypt -i output/test15/encrypted.png -o output/test15/decrypted.png --password-file input/keyfile -vvv && $exe decrypt -i output/test15/encrypted.png -o output/test15/decrypted_pw.png -p \"p2w\" -vvv"
+
+cp output/test15/encrypted.png output/test16/
+exec 16 "Remove a key slot" \
+    "$exe slot remove -i output/test16/encrypted.png --password-file input/keyfile -vvv && $exe decrypt -i output/test16/encrypted.png -o output/test16/decrypted.png -p \"p2w\" -vvv"
+
+cp input/test.png output/test17/test.png
+exec 17 "Encryption for two recipients" \
+    "$exe keygen -o output/test17/alice -vvv && $exe keygen -o output/test17/bob -vvv && $exe encrypt -i output/test17/test.png -o output/test17/encrypted.png -r output/test17/alice.pub -r output/test17/bob.pub -vvv"
+
+cp output/test17/encrypted.png output/test17/*.key output/test18/
+exec 18 "Decryption with each recipient's private key" \
+    "$exe decrypt -i output/test18/encrypted.png -o output/test18/alice.png --identity output/test18/alice.key -vvv && $exe decrypt -i output/test18/encrypted.png -o output/test18/bob.png --identity output/test18/bob.key -vvv"
+
+cp input/test.png output/test19/test.png
+exec 19 "Signed encryption, decryption prints the signer without -v" \
+    "$exe keygen -s -o output/test19/signer -vvv && $exe encrypt -i output/test19/test.png -o output/test19/encrypted.png -p \"p2w\" --sign-key output/test19/signer.key -vvv && [ \"\$($exe decrypt -i output/test19/encrypted.png -o output/test19/decrypted.png -p \"p2w\")\" = \"Valid signature by \$(cat output/test19/signer.pub)\" ]"
+
+cp output/test19/encrypted.png output/test19/signer.pub output/test20/
+exec 20 "Verify the signature without password, also after rekey" \
+    "$exe verify -i output/test20/encrypted.png --signer output/test20/signer.pub -vvv && $exe rekey -i output/test20/encrypted.png -p \"p2w\" --new-password \"w2p\" -vvv && $exe verify -i output/test20/encrypted.png --signer output/test20/signer.pub -vvv"
+
+cp input/test.png output/test21/test.png
+exec 21 "Encryption and decryption with AES-256-GCM-SIV" \
+    "$exe encrypt -i output/test21/test.png -o output/test21/encrypted.png -p \"p2w\" --cipher aes-256-gcm-siv -vvv && $exe decrypt -i output/test21/encrypted.png -o output/test21/decrypted.png -p \"p2w\" -vvv"
+
+cp input/test.png output/test22/test.png
+exec 22 "Split encryption and decryption with XChaCha20-Poly1305" \
+    "$exe encrypt -i output/test22/test.png -o output/test22/encrypted.png -p \"p2w\" --cipher xchacha20-poly1305 -s 2 -vvv && $exe decrypt -i output/test22 -o output/test22/decrypted.png -p \"p2w\" -vvv"
+
+if python3 -c "import resource, zlib" 2>/dev/null; then
+    # 2048x2048 RGBA test pattern, 16 MiB of pixels
+    python3 -c '
+import struct, sys, zlib
+w = h = 2048
+raw = b"".join(b"\0" + bytes((x * 7 + y * 13 + c * 51) & 0xff for x in range(w) for c in range(4)) for y in range(h))
+def chunk(kind, data):
+    return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data))
+with open(sys.argv[1], "wb") as f:
+    f.write(b"\x89PNG\r\n\x1a\n" + chunk(b"IHDR", struct.pack(">IIBBBBB", w, h, 8, 6, 0, 0, 0))
+        + chunk(b"IDAT", zlib.compress(raw, 1)) + chunk(b"IEND", b""))' output/test23/large.png
+    echo "p2w rekeyed" > output/test23/newkey
+    # Memory on top of a run that doesn't touch any image has to stay below half of the 16 MiB of pixels
+    exec 23 "Streaming encryption, decryption and rekey in bounded memory" \
+        "base=\$(peak_kib $exe keygen -o output/test23/baseline) && enc=\$(peak_kib $exe encrypt -i output/test23/large.png -o output/test23/encrypted.png --password-file input/keyfile -s 2 --threads 1) && dec=\$(peak_kib $exe decrypt -i output/test23 -o output/test23/decrypted.png --password-file input/keyfile --threads 1) && rek=\$(peak_kib $exe rekey -i output/test23/encrypted.1.png --password-file input/keyfile --new-password-file output/test23/newkey) && echo \"Peak memory: baseline \$base KiB, encrypt \$enc KiB, decrypt \$dec KiB, rekey \$rek KiB\" && [ \$((enc - base)) -lt 8192 ] && [ \$((dec - base)) -lt 8192 ] && [ \$((rek - base)) -lt 8192 ] && $exe decrypt -i output/test23 -o output/test23/rekeyed.png --password-file output/test23/newkey --threads 1 && cmp output/test23/decrypted.png output/test23/rekeyed.png"
+
+    cp output/test23/large.png output/test24/
+    exec 24 "Multi-threaded and single-threaded decryption give identical images" \
+        "$exe encrypt -i output/test24/large.png -o output/test24/encrypted.png --password-file input/keyfile --threads 4 -vvv && $exe decrypt -i output/test24/encrypted.png -o output/test24/threads1.png --password-file input/keyfile --threads 1 -vvv && $exe decrypt -i output/test24/encrypted.png -o output/test24/threads3.png --password-file input/keyfile --threads 3 -vvv && cmp output/test24/threads1.png output/test24/threads3.png"
+else
+    echo "Skipping tests 23 and 24, python3 with the resource module is needed to measure memory"
+fi
+
+cp README.md output/test25/notes.md
+exec 25 "Encryption of a file that isn't an image, restored under its name" \
+    "$exe encrypt -i output/test25/notes.md -o output/test25/encrypted.png -p \"p2w\" -vvv && mkdir -p output/test25/restored && $exe decrypt -i output/test25/encrypted.png -o output/test25/restored -p \"p2w\" -vvv && cmp output/test25/notes.md output/test25/restored/notes.md"
+
+cp input/test.png output/test26/test.png
+exec 26 "Raw split encryption of an image gives back the same file" \
+    "$exe encrypt -i output/test26/test.png -o output/test26/encrypted.png --raw -s 2 -p \"p2w\" -vvv && $exe decrypt -i output/test26 -o output/test26/decrypted.png -p \"p2w\" -vvv && cmp output/test26/test.png output/test26/decrypted.png"
+
+cp input/test.png output/test27/test.png
+exec 27 "Lossless round trip of the original file" \
+    "$exe encrypt -i output/test27/test.png -o output/test27/encrypted.png --lossless -s 2 -p \"p2w\" -vvv && $exe decrypt -i output/test27 -o output/test27/decrypted.png -p \"p2w\" -vvv && [ \"\$(sha256 output/test27/test.png)\" = \"\$(sha256 output/test27/decrypted.png)\" ]"
+
+exec 28 "Round trip of 8 bit, 16 bit and float color types" \
+    "color_round_trip"
+
+cp input/test.png output/test29/test.png
+exec 29 "Compressed encryption with zstd and deflate, smaller than uncompressed" \
+    "$exe encrypt -i output/test29/test.png -o output/test29/plain.png -p \"p2w\" -vvv && $exe encrypt -i output/test29/test.png -o output/test29/zstd.png --compress zstd:19 -p \"p2w\" -vvv && $exe encrypt -i output/test29/test.png -o output/test29/deflate.png --compress deflate -p \"p2w\" -vvv && $exe decrypt -i output/test29/plain.png -o output/test29/plain_decrypted.png -p \"p2w\" -vvv && $exe decrypt -i output/test29/zstd.png -o output/test29/zstd_decrypted.png -p \"p2w\" -vvv && $exe decrypt -i output/test29/deflate.png -o output/test29/deflate_decrypted.png -p \"p2w\" -vvv && cmp output/test29/plain_decrypted.png output/test29/zstd_decrypted.png && cmp output/test29/plain_decrypted.png output/test29/deflate_decrypted.png && [ \$(wc -c < output/test29/zstd.png) -lt \$(wc -c < output/test29/plain.png) ] && [ \$(wc -c < output/test29/deflate.png) -lt \$(wc -c < output/test29/plain.png) ]"
+
+cp input/test.png output/test30/test.png
+mkdir -p output/test30/split
+exec 30 "Square, fixed width and split aspect ratio carrier shapes" \
+    "$exe encrypt -i output/test30/test.png -o output/test30/square.png --carrier-shape square -p \"p2w\" -vvv && is_square output/test30/square.png && $exe decrypt -i output/test30/square.png -o output/test30/square_decrypted.png -p \"p2w\" -vvv && $exe encrypt -i output/test30/test.png -o output/test30/width.png --carrier-shape width:100 -p \"p2w\" -vvv && [ \"\$(png_size output/test30/width.png | cut -d ' ' -f 1)\" = 100 ] && $exe decrypt -i output/test30/width.png -o output/test30/width_decrypted.png -p \"p2w\" -vvv && $exe encrypt -i output/test30/test.png -o output/test30/split/encrypted.png --carrier-shape aspect:1:1 -s 3 -p \"p2w\" -vvv && is_square output/test30/split/encrypted.1.png && $exe decrypt -i output/test30/split -o output/test30/split_decrypted.png -p \"p2w\" -vvv && cmp output/test30/square_decrypted.png output/test30/width_decrypted.png && cmp output/test30/square_decrypted.png output/test30/split_decrypted.png"
+
+cp input/test.png output/test31/test.png
+mkdir -p output/test31/split
+exec 31 "Carriers padded to a size bucket, split parts of equal size" \
+    "$exe encrypt -i output/test31/test.png -o output/test31/encrypted.png --pad 1M --carrier-shape square -p \"p2w\" -vvv && [ \"\$(png_size output/test31/encrypted.png)\" = \"512 512\" ] && $exe decrypt -i output/test31/encrypted.png -o output/test31/decrypted.png -p \"p2w\" -vvv && $exe encrypt -i output/test31/test.png -o output/test31/split/encrypted.png --pad pow2 -s 3 -p \"p2w\" -vvv && [ \"\$(png_size output/test31/split/encrypted.1.png)\" = \"\$(png_size output/test31/split/encrypted.3.png)\" ] && $exe decrypt -i output/test31/split -o output/test31/split_decrypted.png -p \"p2w\" -vvv && cmp output/test31/decrypted.png output/test31/split_decrypted.png"
+
+# Gigapixel images take minutes and about 15 GB of disk, a release build is recommended
+if [ -n "$P2W_LARGE_TESTS" ] && python3 -c "import zlib" 2>/dev/null; then
+    # 65536x16385 RGBA, 256 KiB more than 4 GiB of pixels
+    python3 -c '
+import struct, sys, zlib
+w, h = 65536, 16385
+pattern = bytes((i * 7 + i // 4099 * 13) & 0xff for i in range(w * 4 + 4096))
+def chunk(kind, data):
+    return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data))
+with open(sys.argv[1], "wb") as f:
+    f.write(b"\x89PNG\r\n\x1a\n" + chunk(b"IHDR", struct.pack(">IIBBBBB", w, h, 8, 6, 0, 0, 0)))
+    z = zlib.compressobj(1)
+    for y in range(h):
+        data = z.compress(b"\0" + pattern[y % 4096:y % 4096 + w * 4])
+        if data:
+            f.write(chunk(b"IDAT", data))
+    f.write(chunk(b"IDAT", z.flush()) + chunk(b"IEND", b""))' output/test32/gigapixel.png
+    exec 32 "Image with more than 4 GiB of pixels, spread over two carriers" \
+        "$exe encrypt -i output/test32/gigapixel.png -o output/test32/encrypted.png --password-file input/keyfile -v && [ -f output/test32/encrypted.1.png ] && [ -f output/test32/encrypted.2.png ] && $exe decrypt -i output/test32 -o output/test32/decrypted.png --password-file input/keyfile -v && [ \"\$(png_size output/test32/decrypted.png)\" = \"65536 16385\" ]"
+    rm -rf output/test32
+
+    # Sparse file one byte over 4 GiB
+    truncate -s 4294967297 output/test33/large.bin
+    exec 33 "Raw file one byte over 4 GiB" \
+        "$exe encrypt -i output/test33/large.bin -o output/test33/encrypted.png --password-file input/keyfile -v && $exe decrypt -i output/test33 -o output/test33/decrypted.bin --password-file input/keyfile -v && cmp output/test33/large.bin output/test33/decrypted.bin"
+    rm -rf output/test33
+else
+    echo "Skipping tests 32 and 33, set P2W_LARGE_TESTS=1 to run the gigapixel tests"
+fi
+
+cp input/test.png output/test34/test.png
+mkdir -p output/test34/parts output/test34/other
+exec 34 "Split parts found by their part headers, whatever their names" \
+    "$exe encrypt -i output/test34/test.png -o output/test34/parts/encrypted.png -s 3 -p \"p2w\" -vvv && $exe encrypt -i output/test34/test.png -o output/test34/other/encrypted.png -s 2 -p \"p2w\" -vvv && mv output/test34/parts/encrypted.1.png output/test34/parts/zebra.png && mv output/test34/parts/encrypted.2.png output/test34/parts/apple.png && mv output/test34/parts/encrypted.3.png output/test34/parts/middle.png && cp output/test34/other/encrypted.1.png output/test34/parts/stray.png && $exe decrypt -i output/test34/parts -o output/test34/decrypted.png -p \"p2w\" -vvv && $exe decrypt -i output/test34/parts/middle.png -o output/test34/from_part.png -p \"p2w\" -vvv && $exe decrypt -i output/test34/other/encrypted.1.png -o output/test34/other.png -p \"p2w\" -vvv && cmp output/test34/decrypted.png output/test34/from_part.png && cmp output/test34/decrypted.png output/test34/other.png"
+
+cp input/test.png output/test35/test.png
+mkdir -p output/test35/parts
+exec 35 "Erasure coded split, any 3 of 5 parts decrypt" \
+    "$exe encrypt -i output/test35/test.png -o output/test35/parts/encrypted.png -s 5 --min-parts 3 -p \"p2w\" -vvv && $exe decrypt -i output/test35/parts -o output/test35/all.png -p \"p2w\" -vvv && rm output/test35/parts/encrypted.1.png output/test35/parts/encrypted.4.png && $exe decrypt -i output/test35/parts -o output/test35/three.png -p \"p2w\" -vvv && cmp output/test35/all.png output/test35/three.png && cmp output/test35/all.png output/test34/decrypted.png"
+
+cp input/test.png output/test36/test.png
+exec 36 "Shamir split into 5 shares, any 3 of them merge" \
+    "$exe split -i output/test36/test.png -n 5 -k 3 -vvv && $exe merge --inputs output/test36/test.5.png,output/test36/test.1.png,output/test36/test.3.png --output output/test36/merged_a.png -vvv && $exe merge --inputs output/test36/test.2.png,output/test36/test.4.png,output/test36/test.5.png --output output/test36/merged_b.png -vvv && cmp output/test36/merged_a.png output/test36/merged_b.png && cmp output/test36/merged_a.png output/test3/merged.png"
+
+mkdir -p output/test37/shamir
+cp input/transparent.png output/test37/transparent.png
+cp input/transparent.png output/test37/shamir/transparent.png
+exec 37 "Transparent image survives split and merge with --alpha bit for bit" \
+    "$exe split -i output/test37/transparent.png -n 3 --alpha -vvv && $exe merge --inputs output/test37/transparent.1.png,output/test37/transparent.2.png,output/test37/transparent.3.png --output output/test37/merged.png --alpha -vvv && cmp input/transparent.png output/test37/merged.png && $exe split -i output/test37/shamir/transparent.png -n 5 -k 3 --alpha -vvv && $exe merge --inputs output/test37/shamir/transparent.4.png,output/test37/shamir/transparent.2.png,output/test37/shamir/transparent.5.png --output output/test37/shamir/merged.png -vvv && cmp input/transparent.png output/test37/shamir/merged.png"
+
+cp input/test.png output/test38/test.png
+exec 38 "Password from an environment variable and from a command" \
+    "P2W_TEST_PASSWORD=p2w $exe encrypt -i output/test38/test.png -o output/test38/encrypted.png --password-env P2W_TEST_PASSWORD -vvv && $exe decrypt -i output/test38/encrypted.png -o output/test38/from_command.png --password-command \"echo p2w\" -vvv && $exe decrypt -i output/test38/encrypted.png -o output/test38/from_password.png -p \"p2w\" -vvv && cmp output/test38/from_command.png output/test38/from_password.png"
+
+exec 39 "Decrypt a version 1 image" \
+    "$exe decrypt -i input/legacy_v1.png -o output/test39/decrypted.png -p \"p2w\" -vvv && [ \"\$(png_pixels output/test39/decrypted.png)\" = \"\$(png_pixels input/colors/rgba8.png)\" ]"
+
+cp input/test.png output/test40/test.png
+exec 40 "Rekey and manage key slots with passwords from the environment and commands" \
+    "$exe encrypt -i output/test40/test.png -o output/test40/encrypted.png -p \"p2w\" -vvv && P2W_TEST_PASSWORD=w2p $exe rekey -i output/test40/encrypted.png --password-command \"echo p2w\" --new-password-env P2W_TEST_PASSWORD -vvv && P2W_TEST_PASSWORD=w2p $exe slot add -i output/test40/encrypted.png --password-env P2W_TEST_PASSWORD --new-password-command \"echo f2p\" -vvv && $exe decrypt -i output/test40/encrypted.png -o output/test40/decrypted.png -p \"f2p\" -vvv && $exe slot remove -i output/test40/encrypted.png --password-command \"echo w2p\" -vvv && $exe decrypt -i output/test40/encrypted.png -o output/test40/after_remove.png -p \"f2p\" -vvv && ! $exe decrypt -i output/test40/encrypted.png -o output/test40/fail.png -p \"w2p\" -vvv && cmp output/test40/decrypted.png output/test40/after_remove.png"
+
+cp input/colors/rgb16.tiff output/test41/photo.tiff
+mkdir -p output/test41/again
+cp input/test.png output/test41/again/test.png
+exec 41 "Split parts are always PNG" \
+    "$exe split -i output/test41/photo.tiff -n 3 -k 2 -vvv && [ \"\$(head -c 4 output/test41/photo.1.png | tail -c 3)\" = PNG ] && [ ! -e output/test41/photo.1.tiff ] && $exe merge --inputs output/test41/photo.3.png,output/test41/photo.1.png --output output/test41/merged.png -vvv && $exe split -i output/test41/photo.tiff -n 2 -vvv && [ \"\$(head -c 4 output/test41/photo.2.png | tail -c 3)\" = PNG ] && $exe split -i output/test41/again/test.png -n 3 -k 2 -vvv"
+
+mkdir -p output/test42/parts
+printf 'tiny' > output/test42/tiny.txt
+exec 42 "A file too small to split evenly keeps the header in the first part and fills every part" \
+    "$exe encrypt -i output/test42/tiny.txt -o output/test42/parts/encrypted.png --raw -s 3 -p \"p2w\" -vvv && [ -e output/test42/parts/encrypted.3.png ] && $exe decrypt -i output/test42/parts -o output/test42/decrypted.txt -p \"p2w\" -vvv && cmp output/test42/tiny.txt output/test42/decrypted.txt"
+
 cp input/test.png output/errors/test.png
 cp output/test5/encrypted.png output/errors/
 
@@ -101,6 +364,11 @@ if $exe split -i nonexistent.png -n 2 -vvv; then
     echo "Error case failed: Should have errored on nonexistent file"
     exit 1
 fi
+echo "Testing split into 0 parts..."
+if $exe split -i output/errors/test.png -n 0 -vvv; then
+    echo "Error case failed: Should have errored on 0 parts"
+    exit 1
+fi
 echo "Testing merge with nonexistent parts..."
 if $exe merge --inputs missing1.png,missing2.png --output output/errors/bad.png -vvv; then
     echo "Error case failed: Should have errored on missing input files"
@@ -111,10 +379,151 @@ if $exe encrypt -i output/errors/test.png -o output/errors/fail.png -vvv; then
     echo "Error case failed: Should have errored without password"
     exit 1
 fi
+echo "Testing encryption with an unset password variable..."
+if env -u P2W_TEST_PASSWORD $exe encrypt -i output/errors/test.png -o output/errors/fail.png --password-env P2W_TEST_PASSWORD -vvv; then
+    echo "Error case failed: Should have errored on the unset variable"
+    exit 1
+fi
+echo "Testing decryption with a failing password command..."
+if $exe decrypt -i output/errors/encrypted.png -o output/errors/fail.png --password-command "false" -vvv; then
+    echo "Error case failed: Should have errored on the failing command"
+    exit 1
+fi
 echo "Testing decryption with wrong password..."
 if $exe decrypt -i output/errors/encrypted.png -o output/errors/fail.png -p "f2p" -vvv; then
     echo "Error case failed: Should have errored with wrong password"
     exit 1
 fi
+echo "Testing decryption with the password replaced by rekey..."
+if $exe decrypt -i output/test14 -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have errored with the old password"
+    exit 1
+fi
+echo "Testing decryption with a removed key slot..."
+if $exe decrypt -i output/test16/encrypted.png -o output/errors/fail.png --password-file input/keyfile -vvv; then
+    echo "Error case failed: Should have errored with the removed key file"
+    exit 1
+fi
+echo "Testing decryption with a missing split part..."
+rm output/test34/parts/apple.png
+if $exe decrypt -i output/test34/parts/zebra.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have errored on the missing part"
+    exit 1
+fi
+echo "Testing decryption with fewer erasure coded parts than needed..."
+rm output/test35/parts/encrypted.2.png
+if $exe decrypt -i output/test35/parts -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have errored with 2 of the 3 parts needed"
+    exit 1
+fi
+echo "Testing merge with fewer Shamir shares than the threshold..."
+if $exe merge --inputs output/test36/test.1.png,output/test36/test.2.png --output output/errors/fail.png -vvv; then
+    echo "Error case failed: Should have errored with 2 of the 3 shares needed"
+    exit 1
+fi
+echo "Testing merge with Shamir shares of two different splits..."
+if $exe merge --inputs output/test36/test.1.png,output/test36/test.2.png,output/test41/again/test.3.png --output output/errors/fail.png -vvv; then
+    echo "Error case failed: Should have errored on shares of another split"
+    exit 1
+fi
+echo "Testing removal of the last key slot..."
+if $exe slot remove -i output/test16/encrypted.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused to remove the last key slot"
+    exit 1
+fi
+echo "Testing decryption with a private key that isn't a recipient..."
+$exe keygen -o output/errors/eve -vvv
+if $exe decrypt -i output/test18/encrypted.png -o output/errors/fail.png --identity output/errors/eve.key -vvv; then
+    echo "Error case failed: Should have errored with a foreign private key"
+    exit 1
+fi
+echo "Testing verification of an unsigned image..."
+if $exe verify -i output/test5/encrypted.png -vvv; then
+    echo "Error case failed: Should have errored on an unsigned image"
+    exit 1
+fi
+echo "Testing verification with the wrong signer..."
+$exe keygen -s -o output/errors/mallory -vvv
+if $exe verify -i output/test20/encrypted.png --signer output/errors/mallory.pub -vvv; then
+    echo "Error case failed: Should have errored with the wrong signer"
+    exit 1
+fi
+echo "Testing decryption with a huge Argon2 memory cost in the header..."
+cp output/errors/encrypted.png output/errors/kdf.png
+png_pixels output/errors/kdf.png 74 ffffffff
+if $exe decrypt -i output/errors/kdf.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused the memory cost before hashing"
+    exit 1
+fi
+echo "Testing decryption with a huge Argon2 time cost in the header..."
+cp output/errors/encrypted.png output/errors/kdf_t.png
+png_pixels output/errors/kdf_t.png 78 0fffffff
+if $exe decrypt -i output/errors/kdf_t.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused the time cost before hashing"
+    exit 1
+fi
+echo "Testing decryption with a huge Argon2 parallelism in the header..."
+cp output/errors/encrypted.png output/errors/kdf_p.png
+png_pixels output/errors/kdf_p.png 82 00ffffff
+if $exe decrypt -i output/errors/kdf_p.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused the parallelism before hashing"
+    exit 1
+fi
+echo "Testing decryption above the configured Argon2 iteration limit..."
+if P2W_MAX_KDF_T_COST=1 $exe decrypt -i output/errors/encrypted.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused the time cost above P2W_MAX_KDF_T_COST"
+    exit 1
+fi
+echo "Testing decryption above the configured Argon2 memory limit..."
+if P2W_MAX_KDF_M_COST=1024 $exe decrypt -i output/errors/encrypted.png -o output/errors/fail.png -p "p2w" -vvv; then
+    echo "Error case failed: Should have refused the memory cost above P2W_MAX_KDF_M_COST"
+    exit 1
+fi
+echo "Testing exit codes..."
+$exe encrypt -i input/colors/rgb8.png -o output/errors/rgb8.png -p "p2w" -vvv
+# Width, height and payload length in the core header, the color record after the key slot
+for field in "width 37 00000001" "height 41 00000001" "payload_len 52 00" "color 160 01"; do
+    set -- $field
+    cp output/errors/rgb8.png output/errors/tampered.png
+    png_pixels output/errors/tampered.png $2 $3
+    $exe decrypt -i output/errors/tampered.png -o output/errors/fail.png -p "p2w" -vvv
+    code=$?
+    if [ $code -ne 4 ]; then
+        echo "Error case failed: A modified $1 should exit with 4, got $code"
+        exit 1
+    fi
+done
+$exe decrypt -i output/errors/encrypted.png -o output/errors/fail.png -p "f2p" -vvv
+code=$?
+if [ $code -ne 3 ]; then
+    echo "Error case failed: A wrong password should exit with 3, got $code"
+    exit 1
+fi
+$exe decrypt -i input/legacy_v1.png -o output/errors/fail.png -p "f2p" -vvv
+code=$?
+if [ $code -ne 3 ]; then
+    echo "Error case failed: A wrong password for a version 1 image should exit with 3, got $code"
+    exit 1
+fi
+cp output/errors/rgb8.png output/errors/tampered.png
+png_pixels output/errors/tampered.png 160 7f
+$exe decrypt -i output/errors/tampered.png -o output/errors/fail.png -p "p2w" -vvv
+code=$?
+if [ $code -ne 5 ]; then
+    echo "Error case failed: An unknown color type should exit with 5, got $code"
+    exit 1
+fi
+$exe decrypt -i output/errors/test.png -o output/errors/fail.png -p "p2w" -vvv
+code=$?
+if [ $code -ne 5 ]; then
+    echo "Error case failed: An image that isn't encrypted should exit with 5, got $code"
+    exit 1
+fi
+$exe decrypt -i output/test34/parts/zebra.png -o output/errors/fail.png -p "p2w" -vvv
+code=$?
+if [ $code -ne 6 ]; then
+    echo "Error case failed: A missing split part should exit with 6, got $code"
+    exit 1
+fi
 echo -e "${GREEN}All tests passed.${N}"
 rm -rf output
\ No newline at end of file